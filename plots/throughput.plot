@@ -1,10 +1,13 @@
 # Copyright 2020 Joyent, Inc.
 #
-# Assumes data file named 'chum.out' with data printed on a five-second
-# interval.
+# Assumes data file named 'chum.out' produced by `chum ... -f t --header` with
+# data printed on a five-second interval. Columns are looked up by name, so
+# this needs gnuplot 5.4 or later.
 #
 datafile = 'chum.out'
 
+set datafile columnheaders
+
 set title "Throughput" font ",15"
 set xlabel "Time" norotate font ",15"
 set ylabel "Throughput (MB/s)" font ",15"
@@ -18,6 +21,6 @@ set grid xtics ytics
 to_mb(x) = (x/1024/1024/5)
 
 plot \
-datafile u 1:(to_mb($5)) w points pt 7 ps 0.5 lc rgb "blue" t "write", \
-datafile u 1:(to_mb($4)) w points pt 7 ps 0.5 lc rgb "red" t "read", \
-datafile u 1:(to_mb($5)+to_mb($4)) w points pt 7 ps 0.5 lc rgb "green" t "combined"
+datafile u "time":(to_mb(column("write_bytes"))) w points pt 7 ps 0.5 lc rgb "blue" t "write", \
+datafile u "time":(to_mb(column("read_bytes"))) w points pt 7 ps 0.5 lc rgb "red" t "read", \
+datafile u "time":(to_mb(column("write_bytes"))+to_mb(column("read_bytes"))) w points pt 7 ps 0.5 lc rgb "green" t "combined"
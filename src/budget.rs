@@ -0,0 +1,219 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::utils::DataCap;
+use crate::worker::Operation;
+
+/*
+ * An OpBudget enforces the operation count caps of a run. The stat thread only
+ * looks at the results once per tick, so it can't stop a run at an exact
+ * count. Instead each worker claims a slot from the budget before it starts
+ * an operation, and the worker that completes the last slot stops the run.
+ *
+ * A slot is given back when its operation fails or turns out to be a no-op
+ * (like a read of an empty queue), so that another operation can take its
+ * place. Reads that fail verification keep their slot, since they did reach
+ * the target.
+ *
+ * An operation may start while any cap that covers it has room left. When
+ * caps overlap (e.g. '10,d:5') one may go past its count while the other is
+ * filled. Operations without a cap aren't limited.
+ *
+ * Operations started before the budget is armed, during the warmup, don't
+ * count. Those still under way when the warmup ends land in the run's totals,
+ * so a run with a warmup may go past its caps by up to one operation per
+ * worker.
+ */
+pub struct OpBudget {
+    caps: Vec<(Option<Operation>, u64)>,
+    claimed: Vec<AtomicU64>, /* operations started, per cap. */
+    done: Vec<AtomicU64>,    /* operations completed, per cap. */
+    armed: AtomicBool,
+}
+
+impl OpBudget {
+    /* Returns None if none of the caps are operation count caps. */
+    pub fn new(caps: &[DataCap], armed: bool) -> Option<OpBudget> {
+        let caps: Vec<(Option<Operation>, u64)> = caps
+            .iter()
+            .filter_map(|cap| match cap {
+                DataCap::Operations(c) => Some(c.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        if caps.is_empty() {
+            return None;
+        }
+
+        Some(OpBudget {
+            claimed: caps.iter().map(|_| AtomicU64::new(0)).collect(),
+            done: caps.iter().map(|_| AtomicU64::new(0)).collect(),
+            caps,
+            armed: AtomicBool::new(armed),
+        })
+    }
+
+    /* The indexes of the caps that count 'op'. */
+    fn covering(&self, op: Operation) -> impl Iterator<Item = usize> + '_ {
+        self.caps
+            .iter()
+            .enumerate()
+            .filter(move |(_, (o, _))| o.is_none() || *o == Some(op))
+            .map(|(i, _)| i)
+    }
+
+    /*
+     * Claim a slot for 'op'. Returns None if there's no room left for it, or
+     * whether the operation counts toward the caps.
+     */
+    pub fn claim(&self, op: Operation) -> Option<bool> {
+        if !self.armed.load(Ordering::SeqCst) {
+            return Some(false);
+        }
+
+        let covering: Vec<usize> = self.covering(op).collect();
+        if covering.is_empty() {
+            return Some(false);
+        }
+
+        let mut full = Vec::new();
+        for &i in covering.iter() {
+            let limit = self.caps[i].1;
+            let reserved = self.claimed[i]
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    if n < limit {
+                        Some(n + 1)
+                    } else {
+                        None
+                    }
+                })
+                .is_ok();
+            if !reserved {
+                full.push(i);
+            }
+        }
+        if full.len() == covering.len() {
+            return None;
+        }
+
+        /* Caps that were already full still count the operation. */
+        for i in full {
+            self.claimed[i].fetch_add(1, Ordering::SeqCst);
+        }
+        Some(true)
+    }
+
+    /* Give back a slot claimed for an operation that didn't complete. */
+    pub fn release(&self, op: Operation, counted: bool) {
+        if counted {
+            for i in self.covering(op) {
+                self.claimed[i].fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /*
+     * Count a completed operation. Returns true once every cap has been
+     * reached.
+     */
+    pub fn finish(&self, op: Operation, counted: bool) -> bool {
+        if counted {
+            for i in self.covering(op) {
+                self.done[i].fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        self.reached()
+    }
+
+    pub fn reached(&self) -> bool {
+        self.armed.load(Ordering::SeqCst)
+            && self
+                .caps
+                .iter()
+                .zip(self.done.iter())
+                .all(|((_, limit), n)| n.load(Ordering::SeqCst) >= *limit)
+    }
+
+    /* Start counting operations, once the warmup is over. */
+    pub fn arm(&self) {
+        self.armed.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::parse_operation_caps;
+
+    fn budget(caps: &str) -> OpBudget {
+        let caps = [DataCap::Operations(parse_operation_caps(caps).unwrap())];
+        OpBudget::new(&caps, true).unwrap()
+    }
+
+    #[test]
+    fn test_budget_total() {
+        let b = budget("3");
+        for _ in 0..3 {
+            assert_eq!(b.claim(Operation::Write), Some(true));
+        }
+        assert_eq!(b.claim(Operation::Read), None);
+
+        /* A failed operation makes room for another. */
+        b.release(Operation::Write, true);
+        assert_eq!(b.claim(Operation::Read), Some(true));
+
+        assert!(!b.finish(Operation::Write, true));
+        assert!(!b.finish(Operation::Write, true));
+        assert!(b.finish(Operation::Read, true));
+    }
+
+    #[test]
+    fn test_budget_per_op() {
+        let b = budget("w:1,d:1");
+
+        /* Reads aren't capped. */
+        assert_eq!(b.claim(Operation::Read), Some(false));
+        assert_eq!(b.claim(Operation::Write), Some(true));
+        assert_eq!(b.claim(Operation::Write), None);
+        assert!(!b.finish(Operation::Write, true));
+        assert_eq!(b.claim(Operation::Delete), Some(true));
+        assert!(b.finish(Operation::Delete, true));
+    }
+
+    #[test]
+    fn test_budget_overlap() {
+        let b = budget("1,d:2");
+        assert_eq!(b.claim(Operation::Read), Some(true));
+        assert_eq!(b.claim(Operation::Read), None);
+
+        /* The total is full, but deletes still have room. */
+        assert_eq!(b.claim(Operation::Delete), Some(true));
+        assert_eq!(b.claim(Operation::Delete), Some(true));
+        assert_eq!(b.claim(Operation::Delete), None);
+        assert!(!b.finish(Operation::Read, true));
+        assert!(!b.finish(Operation::Delete, true));
+        assert!(b.finish(Operation::Delete, true));
+    }
+
+    #[test]
+    fn test_budget_warmup() {
+        let caps = [DataCap::Operations(vec![(None, 1)])];
+        let b = OpBudget::new(&caps, false).unwrap();
+        assert_eq!(b.claim(Operation::Write), Some(false));
+        assert!(!b.finish(Operation::Write, false));
+
+        b.arm();
+        assert_eq!(b.claim(Operation::Write), Some(true));
+        assert!(b.finish(Operation::Write, true));
+
+        assert!(OpBudget::new(&[DataCap::LogicalData(1)], true).is_none());
+    }
+}
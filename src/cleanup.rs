@@ -0,0 +1,279 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use serde_json::json;
+
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::fs::FsCleaner;
+use crate::s3::S3Cleaner;
+use crate::utils::{unix_time, ChumError, OutputFormat};
+use crate::webdav::WebDavCleaner;
+
+/*
+ * Objects are listed and deleted in batches of up to this many. This is the
+ * most that an S3 DeleteObjects request takes.
+ */
+pub const BATCH_SIZE: usize = 1000;
+
+/* How often the main thread checks whether the deleters are done. */
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/*
+ * A Cleaner finds and deletes the objects that chum wrote to a target. Only
+ * objects named the way the backends name them are touched, so anything else
+ * on the target is left alone.
+ */
+pub trait Cleaner: Send {
+    /*
+     * Pass every object chum wrote to 'found', a batch at a time, until
+     * 'found' returns false.
+     */
+    fn list(
+        &mut self,
+        found: &mut dyn FnMut(Vec<String>) -> bool,
+    ) -> Result<(), ChumError>;
+
+    /*
+     * Delete a batch of objects, returning an error for each one that
+     * failed.
+     */
+    fn delete(&mut self, objs: &[String]) -> Vec<ChumError>;
+
+    /* Tidy up once every object is gone, e.g. by removing empty directories. */
+    fn finish(&mut self) -> Result<(), ChumError> {
+        Ok(())
+    }
+}
+
+fn new_cleaner(protocol: &str, target: &str) -> Box<dyn Cleaner> {
+    match protocol {
+        "webdav" => Box::new(WebDavCleaner::new(target)),
+        "s3" => Box::new(S3Cleaner::new(target)),
+        "fs" => Box::new(FsCleaner::new(target)),
+        _ => panic!("unknown client protocol"),
+    }
+}
+
+#[derive(Default)]
+struct Progress {
+    found: AtomicU64,
+    deleted: AtomicU64,
+    errors: AtomicU64,
+}
+
+fn print_progress(
+    format: &OutputFormat,
+    progress: &Progress,
+    start: Instant,
+    done: Option<bool>, /* whether the cleanup was interrupted, once over */
+) {
+    let found = progress.found.load(Ordering::SeqCst);
+    let deleted = progress.deleted.load(Ordering::SeqCst);
+    let errors = progress.errors.load(Ordering::SeqCst);
+    let elapsed = start.elapsed();
+
+    match format {
+        OutputFormat::Json | OutputFormat::JsonVerbose => {
+            let mut obj = json!({
+                "type": if done.is_some() { "summary" } else { "cleanup" },
+                "time": unix_time(),
+                "elapsed": elapsed.as_secs_f64(),
+                "found": found,
+                "deleted": deleted,
+                "errors": errors,
+            });
+            if let Some(interrupted) = done {
+                obj["interrupted"] = json!(interrupted);
+            }
+            println!("{}", obj);
+        }
+        _ => {
+            if done.is_some() {
+                println!("===");
+                print!("Summary ({}s)\t", elapsed.as_secs());
+            } else {
+                print!("Cleanup\t");
+            }
+            let rate = deleted as f64 / elapsed.as_secs_f64().max(1.0);
+            println!(
+                "{} found, {} deleted, {} errors, avg {:.0} objs/s",
+                found, deleted, errors, rate
+            );
+        }
+    }
+}
+
+/* Delete batches of objects handed out by the lister until there are none. */
+fn delete_batches(
+    mut cleaner: Box<dyn Cleaner>,
+    batches: Arc<Mutex<Receiver<Vec<String>>>>,
+    progress: Arc<Progress>,
+    stop: Arc<AtomicBool>,
+) {
+    while !stop.load(Ordering::SeqCst) {
+        let batch = match batches.lock().unwrap().recv() {
+            Ok(batch) => batch,
+            Err(_) => return, /* the lister is done */
+        };
+
+        let errors = cleaner.delete(&batch);
+        for e in errors.iter() {
+            eprintln!("{}", e);
+        }
+        progress
+            .deleted
+            .fetch_add((batch.len() - errors.len()) as u64, Ordering::SeqCst);
+        progress
+            .errors
+            .fetch_add(errors.len() as u64, Ordering::SeqCst);
+    }
+}
+
+/*
+ * Delete everything chum wrote to 'target'. One thread lists the objects and
+ * hands them out in batches to 'conc' deleting threads. Progress is printed
+ * every 'interval', and a summary once every object has been deleted or the
+ * user interrupts the cleanup.
+ */
+pub fn cleanup(
+    protocol: &str,
+    target: &str,
+    conc: u32,
+    interval: Duration,
+    format: &OutputFormat,
+    interrupted: &Arc<AtomicBool>,
+) -> Result<(), Box<dyn Error>> {
+    let progress = Arc::new(Progress::default());
+    let start = Instant::now();
+
+    /* Keep the lister from getting too far ahead of the deleters. */
+    let (batch_tx, batch_rx) = sync_channel(conc as usize * 2);
+    let batch_rx = Arc::new(Mutex::new(batch_rx));
+
+    let (done_tx, done_rx) = channel();
+    for _ in 0..conc {
+        let cleaner = new_cleaner(protocol, target);
+        let batches = batch_rx.clone();
+        let progress = progress.clone();
+        let stop = interrupted.clone();
+        let done_tx = done_tx.clone();
+        thread::spawn(move || {
+            delete_batches(cleaner, batches, progress, stop);
+            let _ = done_tx.send(());
+        });
+    }
+    drop(done_tx);
+
+    /*
+     * Once the deleters have all stopped, the lister's sends fail rather than
+     * waiting for room in the channel.
+     */
+    drop(batch_rx);
+
+    let mut lister = new_cleaner(protocol, target);
+    let lister_progress = progress.clone();
+    let lister_stop = interrupted.clone();
+    let lister = thread::spawn(move || {
+        let res = lister.list(&mut |batch| {
+            let n = batch.len() as u64;
+            if lister_stop.load(Ordering::SeqCst)
+                || batch_tx.send(batch).is_err()
+            {
+                return false;
+            }
+            lister_progress.found.fetch_add(n, Ordering::SeqCst);
+            true
+        });
+        (lister, res)
+    });
+
+    let mut last_print = Instant::now();
+    while done_rx.recv_timeout(POLL_INTERVAL)
+        != Err(RecvTimeoutError::Disconnected)
+    {
+        if last_print.elapsed() >= interval {
+            print_progress(format, &progress, start, None);
+            last_print = Instant::now();
+        }
+    }
+
+    let (mut lister, res) = lister
+        .join()
+        .map_err(|_| ChumError::new("lister thread panicked"))?;
+    let was_interrupted = interrupted.load(Ordering::SeqCst);
+    if res.is_ok() && !was_interrupted {
+        lister.finish()?;
+    }
+    print_progress(format, &progress, start, Some(was_interrupted));
+
+    res.map_err(|e| {
+        Box::new(ChumError::new(&format!("failed to list objects: {}", e)))
+            as Box<dyn Error>
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webdav::propfind_names;
+    use std::path::Path;
+
+    const OBJ: &str = "5e0c8a3c-7f3e-4f0b-9a7e-2d8e1f6a9b10";
+
+    #[test]
+    fn test_fs_cleaner() -> Result<(), ChumError> {
+        let target = std::env::temp_dir()
+            .join(format!("chum-cleanup-test-{}", std::process::id()));
+        let shard = target.join("2020").join("101").join("0");
+        std::fs::create_dir_all(&shard)?;
+        std::fs::write(shard.join(OBJ), b"chum")?;
+        std::fs::write(shard.join("notes.txt"), b"not chum's")?;
+        std::fs::create_dir_all(target.join("2020").join("102").join("1"))?;
+
+        let mut cleaner = FsCleaner::new(&target.to_string_lossy());
+        let mut found = Vec::new();
+        cleaner.list(&mut |batch| {
+            found.extend(batch);
+            true
+        })?;
+        assert_eq!(found, vec![shard.join(OBJ).to_string_lossy().to_string()]);
+
+        /* Deleting an object twice isn't an error. */
+        assert!(cleaner.delete(&found).is_empty());
+        assert!(cleaner.delete(&found).is_empty());
+        cleaner.finish()?;
+
+        /* Only the directories left empty are removed. */
+        assert!(shard.join("notes.txt").exists());
+        assert!(!Path::new(&target.join("2020").join("102")).exists());
+
+        std::fs::remove_dir_all(&target)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_propfind_names() {
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+            <D:multistatus xmlns:D="DAV:">
+              <D:response><D:href>/api/v1/object/</D:href></D:response>
+              <D:response><D:href>/api/v1/object/{}</D:href></D:response>
+              <D:response><D:href>/api/v1/object/notes.txt</D:href></D:response>
+              <response><href>http://host/api/v1/object/{}</href></response>
+            </D:multistatus>"#,
+            OBJ, OBJ
+        );
+        assert_eq!(propfind_names(&body), vec![OBJ, OBJ]);
+    }
+}
@@ -0,0 +1,281 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use clap::ArgMatches;
+use serde_json::{json, Map, Value};
+
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::utils::{unix_time, ChumError, OutputFormat};
+
+/*
+ * A config file supplies the same settings as the command line, as a JSON
+ * object keyed by the long flag names:
+ *
+ *   {
+ *     "target": "127.0.0.1:9000",
+ *     "concurrency": 50,
+ *     "workload": "r:8,w:2",
+ *     "distribution": ["4k-64k:8", "1m-4m:2"],
+ *     "duration": "30m",
+ *     "verify": true
+ *   }
+ *
+ * Values can be strings or numbers. Lists are joined with commas, so
+ * distributions and workloads can be written one entry per line. Flags that
+ * don't take a value are turned on with 'true'.
+ *
+ * The settings are handed to clap along with the command line, so they're
+ * checked exactly like flags are. Anything also given on the command line is
+ * taken from the command line.
+ */
+pub type Config = Map<String, Value>;
+
+pub fn load_config(path: &str) -> Result<Config, ChumError> {
+    let file = File::open(path).map_err(|e| {
+        ChumError::new(&format!("failed to open config file: {}", e))
+    })?;
+    let val: Value =
+        serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+            ChumError::new(&format!("failed to parse config file: {}", e))
+        })?;
+
+    match val {
+        Value::Object(config) => {
+            if config.contains_key("config") {
+                return Err(ChumError::new(
+                    "config files can't include other config files",
+                ));
+            }
+            Ok(config)
+        }
+        _ => Err(ChumError::new("config file must contain a JSON object")),
+    }
+}
+
+/* A single setting in the form it takes on the command line. */
+fn setting_to_arg(key: &str, val: &Value) -> Result<Option<String>, ChumError> {
+    let scalar = |v: &Value| match v {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    };
+
+    let value = match val {
+        Value::Bool(true) => return Ok(Some(format!("--{}", key))),
+        Value::Bool(false) => return Ok(None),
+        Value::Array(vals) => vals
+            .iter()
+            .map(scalar)
+            .collect::<Option<Vec<String>>>()
+            .map(|v| v.join(",")),
+        v => scalar(v),
+    };
+
+    match value {
+        /* Values can start with a '-', so keep them attached to the flag. */
+        Some(v) => Ok(Some(format!("--{}={}", key, v))),
+        None => Err(ChumError::new(&format!(
+            "config setting '{}' must be a string, number, list, or boolean",
+            key
+        ))),
+    }
+}
+
+/*
+ * Turn the settings in a config file into command line arguments, skipping
+ * those that 'on_cli' says were given on the command line.
+ */
+pub fn config_args<F>(
+    config: &Config,
+    on_cli: F,
+) -> Result<Vec<String>, ChumError>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut args = Vec::new();
+    for (key, val) in config.iter() {
+        if on_cli(key) {
+            continue;
+        }
+        if let Some(arg) = setting_to_arg(key, val)? {
+            args.push(arg);
+        }
+    }
+    Ok(args)
+}
+
+/*
+ * The settings a run ended up with, from the config file and the command line,
+ * in the same form as a config file. Settings that were left out are given
+ * their values from 'defaults'.
+ */
+pub fn effective_config(
+    matches: &ArgMatches,
+    names: &[&str],
+    defaults: &[(&str, String)],
+) -> Config {
+    let mut config = Config::new();
+    for name in names {
+        if *name == "config" || !matches.is_present(name) {
+            continue;
+        }
+        let val = match matches.value_of(name) {
+            Some(v) => json!(v),
+            None => json!(true),
+        };
+        config.insert(name.to_string(), val);
+    }
+    for (name, val) in defaults {
+        config.entry(*name).or_insert_with(|| json!(val));
+    }
+    config
+}
+
+/*
+ * Print the settings for the run at startup. Tabular and CSV output only
+ * include them with the rest of the header, as a comment, so headerless output
+ * stays the same.
+ */
+pub fn print_config(format: &OutputFormat, header: bool, config: &Config) {
+    match format {
+        OutputFormat::Human | OutputFormat::HumanVerbose => {
+            println!("Config");
+            for (key, val) in config.iter() {
+                match val {
+                    Value::String(s) => println!("\t{}: {}", key, s),
+                    v => println!("\t{}: {}", key, v),
+                }
+            }
+        }
+        OutputFormat::Json | OutputFormat::JsonVerbose => println!(
+            "{}",
+            json!({
+                "type": "config",
+                "time": unix_time(),
+                "config": config,
+            })
+        ),
+        OutputFormat::Tabular | OutputFormat::Csv => {
+            if header {
+                println!("# config {}", Value::Object(config.clone()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{App, Arg};
+
+    fn config(val: Value) -> Config {
+        match val {
+            Value::Object(config) => config,
+            _ => panic!("not an object"),
+        }
+    }
+
+    #[test]
+    fn test_config_args() -> Result<(), ChumError> {
+        let conf = config(json!({
+            "target": "127.0.0.1:9000",
+            "concurrency": 50,
+            "distribution": ["4k-64k:8", "1m"],
+            "max-ops": "-1",
+            "verify": true,
+            "no-sync": false,
+        }));
+
+        /* serde_json keeps keys sorted. */
+        assert_eq!(
+            config_args(&conf, |_| false)?,
+            vec![
+                "--concurrency=50",
+                "--distribution=4k-64k:8,1m",
+                "--max-ops=-1",
+                "--target=127.0.0.1:9000",
+                "--verify",
+            ]
+        );
+        assert_eq!(
+            config_args(&conf, |key| key != "verify")?,
+            vec!["--verify"]
+        );
+
+        assert_eq!(
+            config_args(&config(json!({ "workload": { "r": 8 } })), |_| false),
+            Err(ChumError::new(
+                "config setting 'workload' must be a string, number, list, \
+                 or boolean"
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_effective_config() -> Result<(), ChumError> {
+        let app = App::new("test").args(&[
+            Arg::with_name("config").long("config").takes_value(true),
+            Arg::with_name("target").long("target").takes_value(true),
+            Arg::with_name("concurrency")
+                .long("concurrency")
+                .takes_value(true),
+            Arg::with_name("workload")
+                .long("workload")
+                .takes_value(true),
+            Arg::with_name("verify").long("verify"),
+            Arg::with_name("no-sync").long("no-sync"),
+        ]);
+
+        /* The command line wins over the config file. */
+        let conf = config(json!({
+            "target": "/tmp",
+            "concurrency": 4,
+            "verify": true,
+        }));
+        let mut argv = vec!["test", "--concurrency", "8", "--config", "x"]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        argv.extend(config_args(&conf, |key| key == "concurrency")?);
+        let matches = app.get_matches_from(argv);
+
+        let names = [
+            "config",
+            "target",
+            "concurrency",
+            "workload",
+            "verify",
+            "no-sync",
+        ];
+        assert_eq!(
+            Value::Object(effective_config(&matches, &names, &[])),
+            json!({ "target": "/tmp", "concurrency": "8", "verify": true })
+        );
+
+        /* Defaults fill in for settings that were left out. */
+        let defaults = [
+            ("concurrency", "1".to_string()),
+            ("workload", "r,w".to_string()),
+        ];
+        assert_eq!(
+            Value::Object(effective_config(&matches, &names, &defaults)),
+            json!({
+                "target": "/tmp",
+                "concurrency": "8",
+                "workload": "r,w",
+                "verify": true,
+            })
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,493 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use rand::distributions::{Distribution, LogNormal, Normal, Pareto, Uniform};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+
+use crate::utils::{
+    convert_numeric_distribution, expand_distribution, parse_human, ChumError,
+};
+
+/*
+ * Largest object the parametric distributions will produce unless the user
+ * provides their own maximum. Heavy-tailed distributions occasionally produce
+ * enormous values, and some backends buffer whole objects in memory.
+ */
+const DEF_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+/*
+ * How object sizes are chosen for writes. Sizes are sampled once per write.
+ *
+ * A list is what users have always been able to pass to '-d'. Each entry is
+ * either a single size or an inclusive range of sizes, and each entry is
+ * equally likely to be chosen. Sizes within a range are uniformly
+ * distributed. Entries can be repeated with the same ':N' shorthand as
+ * before:
+ *   128k,256k,512k
+ *   4k-64k:8,1m-4m:2
+ *
+ * The parametric distributions take the whole argument:
+ *   lognormal:<median>:<sigma>[:<max>]
+ *   pareto:<min>:<shape>[:<max>]
+ *   normal:<mean>:<std dev>[:<max>]
+ *
+ * Sizes drawn from these are capped at 'max'.
+ *
+ * A histogram is loaded from a file named with '@' (e.g. '@sizes.csv'). Each
+ * line of the file describes a bucket, as either
+ *   <low>-<high>,<weight>
+ * or
+ *   <low>,<high>,<weight>
+ *
+ * Sizes can be bare numbers of bytes or have a unit suffix. Weights can be
+ * counts, fractions, or percentages (e.g. '12.5%', the same as 0.125), and
+ * don't need to add up to anything in particular. Blank lines, lines starting
+ * with '#', and a header line without any digits are skipped. A bucket is
+ * chosen in proportion to its weight, and the size is picked uniformly from
+ * within the bucket.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum SizeDistribution {
+    List(Vec<(u64, u64)>), /* inclusive (low, high) ranges */
+    LogNormal {
+        median: u64,
+        sigma: f64,
+        max: u64,
+    },
+    Pareto {
+        min: u64,
+        shape: f64,
+        max: u64,
+    },
+    Normal {
+        mean: u64,
+        std_dev: u64,
+        max: u64,
+    },
+    Histogram {
+        buckets: Vec<(u64, u64)>,
+        cumulative: Vec<f64>,
+    },
+}
+
+impl SizeDistribution {
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> u64 {
+        let (val, max) = match self {
+            SizeDistribution::List(ranges) => {
+                let (low, high) =
+                    ranges.choose(rng).expect("choosing file size failed");
+                return Uniform::new_inclusive(low, high).sample(rng);
+            }
+            SizeDistribution::LogNormal { median, sigma, max } => {
+                let d = LogNormal::new((*median as f64).ln(), *sigma);
+                (d.sample(rng), max)
+            }
+            SizeDistribution::Pareto { min, shape, max } => {
+                (Pareto::new(*min as f64, *shape).sample(rng), max)
+            }
+            SizeDistribution::Normal { mean, std_dev, max } => {
+                (Normal::new(*mean as f64, *std_dev as f64).sample(rng), max)
+            }
+            SizeDistribution::Histogram {
+                buckets,
+                cumulative,
+            } => {
+                let total = cumulative.last().expect("empty histogram");
+                let point = rng.gen::<f64>() * total;
+                let i = match cumulative.binary_search_by(|w| {
+                    w.partial_cmp(&point).expect("invalid histogram weight")
+                }) {
+                    Ok(i) => i + 1, /* landed on a boundary */
+                    Err(i) => i,
+                };
+                let (low, high) = buckets[i.min(buckets.len() - 1)];
+                return Uniform::new_inclusive(low, high).sample(rng);
+            }
+        };
+
+        /* Negative samples (and NaN) become zero-byte objects. */
+        if val > 0.0 {
+            (val.round() as u64).min(*max)
+        } else {
+            0
+        }
+    }
+}
+
+fn parse_shape(name: &str, val: &str) -> Result<f64, ChumError> {
+    match val.parse::<f64>() {
+        Ok(v) if v > 0.0 && v.is_finite() => Ok(v),
+        _ => Err(ChumError::new(&format!(
+            "{} must be a positive number, not '{}'",
+            name, val
+        ))),
+    }
+}
+
+/* Sizes in histogram files are often plain byte counts. */
+fn parse_size(val: &str) -> Result<u64, ChumError> {
+    match val.parse::<u64>() {
+        Ok(size) => Ok(size),
+        Err(_) => parse_human(val),
+    }
+}
+
+/* A percentage is weighted the same as the fraction it stands for. */
+fn parse_weight(val: &str) -> Result<f64, ChumError> {
+    let (num, scale) = match val.strip_suffix('%') {
+        Some(num) => (num, 100.0),
+        None => (val, 1.0),
+    };
+    match num.parse::<f64>() {
+        Ok(w) if w >= 0.0 && w.is_finite() => Ok(w / scale),
+        _ => Err(ChumError::new(&format!(
+            "weight must be a non-negative number, not '{}'",
+            val
+        ))),
+    }
+}
+
+/*
+ * Read a histogram of object sizes. See the comment above SizeDistribution for
+ * the format.
+ */
+fn parse_histogram<R: BufRead>(
+    input: R,
+) -> Result<SizeDistribution, ChumError> {
+    let mut buckets = Vec::new();
+    let mut cumulative = Vec::new();
+    let mut total = 0.0;
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| {
+            ChumError::new(&format!("failed to read size histogram: {}", e))
+        })?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if buckets.is_empty() && !line.chars().any(|c| c.is_ascii_digit()) {
+            continue; /* header */
+        }
+
+        let bucket_err = |e: ChumError| {
+            ChumError::new(&format!("size histogram line {}: {}", i + 1, e))
+        };
+        let tok: Vec<&str> = line.split(',').map(|t| t.trim()).collect();
+        let (low, high, weight) = match tok.as_slice() {
+            [range, weight] => {
+                let sizes: Vec<&str> = range.split('-').collect();
+                match sizes.as_slice() {
+                    [size] => (*size, *size, *weight),
+                    [low, high] => (*low, *high, *weight),
+                    _ => {
+                        return Err(bucket_err(ChumError::new(&format!(
+                            "invalid size range '{}'",
+                            range
+                        ))))
+                    }
+                }
+            }
+            [low, high, weight] => (*low, *high, *weight),
+            _ => {
+                return Err(bucket_err(ChumError::new(
+                    "expected a size range and a weight",
+                )))
+            }
+        };
+        let low = parse_size(low).map_err(bucket_err)?;
+        let high = parse_size(high).map_err(bucket_err)?;
+        let weight = parse_weight(weight).map_err(bucket_err)?;
+        if low > high {
+            return Err(bucket_err(ChumError::new(
+                "size range must go from low to high",
+            )));
+        }
+
+        total += weight;
+        buckets.push((low, high));
+        cumulative.push(total);
+    }
+
+    if total <= 0.0 {
+        return Err(ChumError::new(
+            "size histogram needs at least one bucket with a non-zero weight",
+        ));
+    }
+
+    Ok(SizeDistribution::Histogram {
+        buckets,
+        cumulative,
+    })
+}
+
+/* Parse a single size (e.g. '128k') or an inclusive range (e.g. '4k-1m'). */
+fn parse_range(val: &str) -> Result<(u64, u64), ChumError> {
+    let sizes = convert_numeric_distribution(
+        val.split('-').map(|s| s.to_string()).collect(),
+    )?;
+    match sizes.as_slice() {
+        [size] => Ok((*size, *size)),
+        [low, high] if low <= high => Ok((*low, *high)),
+        [_, _] => Err(ChumError::new(&format!(
+            "size range '{}' must go from low to high",
+            val
+        ))),
+        _ => Err(ChumError::new(&format!("invalid size range '{}'", val))),
+    }
+}
+
+impl FromStr for SizeDistribution {
+    type Err = ChumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix('@') {
+            let file = File::open(path).map_err(|e| {
+                ChumError::new(&format!(
+                    "failed to open size histogram file: {}",
+                    e
+                ))
+            })?;
+            return parse_histogram(BufReader::new(file));
+        }
+
+        let tok: Vec<&str> = s.split(':').collect();
+        let name = tok[0];
+
+        if !["lognormal", "pareto", "normal"].contains(&name) {
+            let mut ranges = Vec::new();
+            for entry in expand_distribution(s)? {
+                ranges.push(parse_range(&entry)?);
+            }
+            return Ok(SizeDistribution::List(ranges));
+        }
+
+        if tok.len() != 3 && tok.len() != 4 {
+            return Err(ChumError::new(&format!(
+                "{} distribution takes two or three parameters",
+                name
+            )));
+        }
+        let max = match tok.get(3) {
+            Some(max) => parse_human(max)?,
+            None => DEF_MAX_SIZE,
+        };
+
+        let dist = match name {
+            "lognormal" => SizeDistribution::LogNormal {
+                median: parse_human(tok[1])?,
+                sigma: parse_shape("sigma", tok[2])?,
+                max,
+            },
+            "pareto" => SizeDistribution::Pareto {
+                min: parse_human(tok[1])?,
+                shape: parse_shape("shape", tok[2])?,
+                max,
+            },
+            _ => SizeDistribution::Normal {
+                mean: parse_human(tok[1])?,
+                std_dev: parse_human(tok[2])?,
+                max,
+            },
+        };
+
+        match dist {
+            SizeDistribution::LogNormal { median: 0, .. }
+            | SizeDistribution::Pareto { min: 0, .. } => Err(ChumError::new(
+                &format!("{} distribution needs a non-zero size", name),
+            )),
+            _ => Ok(dist),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::thread_rng;
+
+    const K: u64 = 1024;
+    const M: u64 = 1024 * 1024;
+
+    fn samples(dist: &SizeDistribution, n: usize) -> Vec<u64> {
+        let mut rng = thread_rng();
+        let mut vals: Vec<u64> =
+            (0..n).map(|_| dist.sample(&mut rng)).collect();
+        vals.sort();
+        vals
+    }
+
+    #[test]
+    fn test_distribution_parse() -> Result<(), ChumError> {
+        assert_eq!(
+            "128k,256k:2".parse::<SizeDistribution>()?,
+            SizeDistribution::List(vec![
+                (128 * K, 128 * K),
+                (256 * K, 256 * K),
+                (256 * K, 256 * K)
+            ])
+        );
+        assert_eq!(
+            "4k-1m,2m".parse::<SizeDistribution>()?,
+            SizeDistribution::List(vec![(4 * K, M), (2 * M, 2 * M)])
+        );
+        assert_eq!(
+            "lognormal:128k:1.5".parse::<SizeDistribution>()?,
+            SizeDistribution::LogNormal {
+                median: 128 * K,
+                sigma: 1.5,
+                max: DEF_MAX_SIZE
+            }
+        );
+        assert_eq!(
+            "pareto:64k:1.2:16m".parse::<SizeDistribution>()?,
+            SizeDistribution::Pareto {
+                min: 64 * K,
+                shape: 1.2,
+                max: 16 * M
+            }
+        );
+        assert_eq!(
+            "normal:1m:256k".parse::<SizeDistribution>()?,
+            SizeDistribution::Normal {
+                mean: M,
+                std_dev: 256 * K,
+                max: DEF_MAX_SIZE
+            }
+        );
+
+        assert_eq!(
+            "1m-4k".parse::<SizeDistribution>(),
+            Err(ChumError::new(
+                "size range '1m-4k' must go from low to high"
+            ))
+        );
+        assert_eq!(
+            "1k-2k-3k".parse::<SizeDistribution>(),
+            Err(ChumError::new("invalid size range '1k-2k-3k'"))
+        );
+        assert_eq!(
+            "pareto:64k:-1".parse::<SizeDistribution>(),
+            Err(ChumError::new("shape must be a positive number, not '-1'"))
+        );
+        assert_eq!(
+            "lognormal:128k".parse::<SizeDistribution>(),
+            Err(ChumError::new(
+                "lognormal distribution takes two or three parameters"
+            ))
+        );
+        assert_eq!(
+            "lognormal:0:1".parse::<SizeDistribution>(),
+            Err(ChumError::new(
+                "lognormal distribution needs a non-zero size"
+            ))
+        );
+        assert!("cauchy:1k:1".parse::<SizeDistribution>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_distribution_sample() -> Result<(), ChumError> {
+        let fixed: SizeDistribution = "128k".parse()?;
+        assert!(samples(&fixed, 100).iter().all(|s| *s == 128 * K));
+
+        let range: SizeDistribution = "4k-8k".parse()?;
+        let vals = samples(&range, 10_000);
+        assert!(vals.iter().all(|s| *s >= 4 * K && *s <= 8 * K));
+        assert!(vals[vals.len() / 2] > 5 * K && vals[vals.len() / 2] < 7 * K);
+
+        /* The median of a lognormal distribution is e^mu. */
+        let lognormal: SizeDistribution = "lognormal:128k:1".parse()?;
+        let vals = samples(&lognormal, 10_000);
+        let median = vals[vals.len() / 2];
+        assert!(median > 100 * K && median < 160 * K, "median {}", median);
+
+        let pareto: SizeDistribution = "pareto:64k:1:1m".parse()?;
+        let vals = samples(&pareto, 10_000);
+        assert!(vals.iter().all(|s| *s >= 64 * K && *s <= M));
+        assert_eq!(*vals.last().unwrap(), M);
+
+        /* Most samples land within three standard deviations. */
+        let normal: SizeDistribution = "normal:1m:128k".parse()?;
+        let vals = samples(&normal, 10_000);
+        assert!(vals[100] > M - 3 * 128 * K && vals[9900] < M + 3 * 128 * K);
+
+        let negative: SizeDistribution = "normal:1k:1m".parse()?;
+        assert_eq!(samples(&negative, 1000)[0], 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_distribution_histogram() -> Result<(), ChumError> {
+        let input = "low,high,weight\n\
+                     # from the object inventory\n\
+                     0,4095,25%\n\
+                     4k-64k, 50%\n\
+                     \n\
+                     1m,1048576,0.25\n";
+        let hist = parse_histogram(input.as_bytes())?;
+        assert_eq!(
+            hist,
+            SizeDistribution::Histogram {
+                buckets: vec![(0, 4095), (4 * K, 64 * K), (M, M)],
+                cumulative: vec![0.25, 0.75, 1.0],
+            }
+        );
+
+        let hist = parse_histogram("0-1k,1\n4k-8k,2\n1m,1\n".as_bytes())?;
+        let vals = samples(&hist, 40_000);
+        let small = vals.iter().filter(|s| **s <= K).count();
+        let medium =
+            vals.iter().filter(|s| **s >= 4 * K && **s <= 8 * K).count();
+        let large = vals.iter().filter(|s| **s == M).count();
+        assert_eq!(small + medium + large, vals.len());
+        assert!(small > 8_000 && small < 12_000, "small {}", small);
+        assert!(medium > 18_000 && medium < 22_000, "medium {}", medium);
+
+        /* Sizes are spread across the bucket. */
+        let medium: Vec<u64> = vals
+            .into_iter()
+            .filter(|s| *s >= 4 * K && *s <= 8 * K)
+            .collect();
+        let mid = medium[medium.len() / 2];
+        assert!(mid > 5 * K && mid < 7 * K, "median {}", mid);
+
+        assert_eq!(
+            parse_histogram("1k-2k,10\n4k,-1\n".as_bytes()),
+            Err(ChumError::new(
+                "size histogram line 2: weight must be a non-negative \
+                 number, not '-1'"
+            ))
+        );
+        assert_eq!(
+            parse_histogram("8k,4k,1\n".as_bytes()),
+            Err(ChumError::new(
+                "size histogram line 1: size range must go from low to high"
+            ))
+        );
+        assert_eq!(
+            parse_histogram("1k,0\n".as_bytes()),
+            Err(ChumError::new(
+                "size histogram needs at least one bucket with a non-zero \
+                 weight"
+            ))
+        );
+        assert!("@/nonexistent/sizes.csv"
+            .parse::<SizeDistribution>()
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -6,22 +6,25 @@
  * Copyright 2020 Joyent, Inc.
  */
 
+use crate::cleanup::{Cleaner, BATCH_SIZE};
+use crate::record::ObjectRecord;
 use crate::state::State;
 use crate::utils::ChumError;
+use crate::verify::{Payload, Verifier};
 use crate::worker::*;
 
-use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::rngs::StdRng;
 use rand::AsByteSliceMut;
 use rand::Rng;
 
 use chrono::{DateTime, Datelike, Utc};
 
 use std::fs::File;
-use std::io::{BufWriter, Read, Write};
+use std::io::{BufWriter, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 use uuid::Uuid;
@@ -32,13 +35,13 @@ pub struct Fs {
     buf: Vec<u8>,
     obj_cnt_dir: u64,
     dir_shard: u32,
+    dir: Option<Arc<str>>,
     wopts: WorkerOptions,
+    rng: StdRng,
 }
 
 impl Fs {
-    pub fn new(wopts: WorkerOptions) -> Fs {
-        let mut rng = thread_rng();
-
+    pub fn new(wopts: WorkerOptions, mut rng: StdRng) -> Fs {
         /*
          * Create a random buffer. This is the data that will be sent
          * to the target.
@@ -53,28 +56,36 @@ impl Fs {
             buf: vec,
             obj_cnt_dir: 0,
             dir_shard: 0,
+            dir: None,
             wopts,
+            rng,
         }
     }
 
-    /* Common function to handle creating filesystem path. */
-    fn get_path(&mut self, fname: String) -> PathBuf {
+    /*
+     * Common function to handle creating filesystem paths. This returns the
+     * directory for the next object, ending in a '/'. Every object in the
+     * directory shares one copy of its name.
+     */
+    fn get_dir(&mut self) -> Arc<str> {
         let today = Utc::today();
         self.obj_cnt_dir += 1;
         if self.obj_cnt_dir > DEF_MAX_DIRENTS {
             self.obj_cnt_dir = 0;
             self.dir_shard += 1;
         }
-        Path::new(&format!(
-            "{}/{}/{}{}/{}/{}",
+        let dir = format!(
+            "{}/{}/{}{}/{}/",
             self.wopts.target,
             today.year(),
             today.month(),
             today.day(),
-            self.dir_shard,
-            fname
-        ))
-        .to_path_buf()
+            self.dir_shard
+        );
+        if self.dir.as_deref() != Some(&dir) {
+            self.dir = Some(dir.into());
+        }
+        self.dir.clone().unwrap()
     }
 
     #[allow(clippy::single_match)]
@@ -100,15 +111,11 @@ impl Fs {
 
 impl Backend for Fs {
     fn write(&mut self) -> Result<Option<WorkerInfo>, ChumError> {
-        let fname = Uuid::new_v4();
-        let mut rng = thread_rng();
-        let size = *self
-            .wopts
-            .distribution
-            .choose(&mut rng)
-            .expect("choosing file size failed");
-
-        let full_path = self.get_path(fname.to_string());
+        let fname = random_uuid(&mut self.rng);
+        let size = self.wopts.distribution.sample(&mut self.rng);
+
+        let dir = self.get_dir();
+        let full_path = PathBuf::from(format!("{}{}", dir, fname));
         let mut begin: DateTime<Utc>;
         let mut end: DateTime<Utc>;
 
@@ -136,8 +143,6 @@ impl Backend for Fs {
         end = Utc::now();
         self.send_state("write::open", begin, end);
 
-        let p = full_path.into_os_string().into_string().expect("failed to convert path to string");
-
         /*
          * Add fallocate support?
          *
@@ -152,17 +157,23 @@ impl Backend for Fs {
 
         let mut bw = BufWriter::new(&file);
 
-        let mut buf: Vec<u8> = Vec::with_capacity(size as usize);
-        let mut bytes_to_go = size;
-        while bytes_to_go > 0 {
-            if bytes_to_go < self.buf.len() as u64 {
-                let tail = &self.buf[0..(bytes_to_go - 1) as usize];
-                buf.extend(tail);
-                break;
+        let buf = if self.wopts.verify {
+            let seed = self.wopts.payload_seed();
+            Payload::new(&fname.to_string(), size, seed).into_vec()
+        } else {
+            let mut buf: Vec<u8> = Vec::with_capacity(size as usize);
+            let mut bytes_to_go = size;
+            while bytes_to_go > 0 {
+                if bytes_to_go < self.buf.len() as u64 {
+                    let tail = &self.buf[0..bytes_to_go as usize];
+                    buf.extend(tail);
+                    break;
+                }
+                buf.extend(&self.buf);
+                bytes_to_go -= self.buf.len() as u64;
             }
-            buf.extend(&self.buf);
-            bytes_to_go -= self.buf.len() as u64;
-        }
+            buf
+        };
 
         /*
          * Write the data to the file and then optionally issue an fsync.
@@ -181,15 +192,10 @@ impl Backend for Fs {
             match file.sync_all() {
                 Err(e) => Err(ChumError::new(&format!("fsync failed: {}", e))),
                 Ok(_) => {
-                    if self.wopts.read_queue {
-                        self.wopts
-                            .queue
-                            .lock()
-                            .unwrap()
-                            .insert(p)
-                    }
-
-                    let rtt = rtt_start.elapsed().as_millis();
+                    let rec = ObjectRecord::new(Some(dir), fname, size);
+                    self.wopts.written(rec, &mut self.rng);
+
+                    let rtt = rtt_start.elapsed();
                     end = Utc::now();
                     self.send_state("write::fsync", begin, end);
 
@@ -197,37 +203,33 @@ impl Backend for Fs {
                         id: thread::current().id(),
                         op: Operation::Write,
                         size,
-                        ttfb: 0, /* not supported */
+                        ttfb: Duration::from_secs(0), /* not supported */
                         rtt,
+                        age: None,
                     }))
                 }
             }
         } else {
-            self.wopts.queue.lock().unwrap().insert(p);
+            let rec = ObjectRecord::new(Some(dir), fname, size);
+            self.wopts.written(rec, &mut self.rng);
 
-            let rtt = rtt_start.elapsed().as_millis();
+            let rtt = rtt_start.elapsed();
             Ok(Some(WorkerInfo {
                 id: thread::current().id(),
                 op: Operation::Write,
                 size,
-                ttfb: 0, /* not supported */
+                ttfb: Duration::from_secs(0), /* not supported */
                 rtt,
+                age: None,
             }))
         }
     }
 
     fn read(&mut self) -> Result<Option<WorkerInfo>, ChumError> {
-        let fname: String;
-        {
-            let mut q = self.wopts.queue.lock().unwrap();
-            let qi = q.get();
-            if qi.is_none() {
-                return Ok(None);
-            }
-            let qi = qi.unwrap();
-
-            fname = qi.clone();
-        }
+        let rec = match self.wopts.queue.get(&mut self.rng) {
+            Some(rec) => rec,
+            None => return Ok(None),
+        };
 
         let mut begin: DateTime<Utc>;
         let mut end: DateTime<Utc>;
@@ -236,7 +238,7 @@ impl Backend for Fs {
 
         let mut buf = Vec::new();
         begin = Utc::now();
-        let mut file = File::open(fname)?;
+        let mut file = File::open(rec.name())?;
         end = Utc::now();
         self.send_state("read::open", begin, end);
 
@@ -245,27 +247,33 @@ impl Backend for Fs {
         end = Utc::now();
         self.send_state("read::read", begin, end);
 
-        let rtt = rtt_start.elapsed().as_millis();
+        let rtt = rtt_start.elapsed();
+
+        /* Objects are verified using their file name, not the full path. */
+        if self.wopts.verify {
+            let name = rec.file_name();
+            let mut verifier = Verifier::new(&name, self.wopts.payload_seed());
+            verifier.update(&buf);
+            verifier.finish()?;
+        }
+        rec.check_read(size as u64, None)?;
 
         Ok(Some(WorkerInfo {
             id: thread::current().id(),
             op: Operation::Read,
             size: size as u64,
-            ttfb: 0,
+            ttfb: Duration::from_secs(0),
             rtt,
+            age: rec.age(),
         }))
     }
 
     fn delete(&mut self) -> Result<Option<WorkerInfo>, ChumError> {
-        let fname: String;
-        {
-            let mut q = self.wopts.queue.lock().unwrap();
-            let qi = q.remove();
-            if qi.is_none() {
-                return Ok(None);
-            }
-            fname = qi.unwrap();
-        }
+        let rec = match self.wopts.queue.remove(&mut self.rng) {
+            Some(rec) => rec,
+            None => return Ok(None),
+        };
+        let fname = rec.name();
         let begin: DateTime<Utc>;
         let end: DateTime<Utc>;
 
@@ -277,7 +285,7 @@ impl Backend for Fs {
         self.send_state("delete::rm", begin, end);
 
         if let Err(e) = res {
-            self.wopts.queue.lock().unwrap().insert(fname.clone());
+            self.wopts.queue.insert(rec, &mut self.rng);
 
             return Err(ChumError::new(&format!(
                 "Deleting {} \
@@ -286,14 +294,114 @@ impl Backend for Fs {
             )));
         }
 
-        let rtt = rtt_start.elapsed().as_millis();
+        let rtt = rtt_start.elapsed();
+        self.wopts.deleted(&rec);
 
         Ok(Some(WorkerInfo {
             id: thread::current().id(),
             op: Operation::Delete,
             size: 0,
-            ttfb: 0,
+            ttfb: Duration::from_secs(0),
             rtt,
+            age: rec.age(),
         }))
     }
 }
+
+/*
+ * Objects are written to <target>/<year>/<month><day>/<shard>/<uuid>. Only that
+ * tree is walked, and only files named with a UUID are deleted.
+ */
+pub struct FsCleaner {
+    target: PathBuf,
+}
+
+/* Subdirectories of 'dir' that are named with a number. */
+fn numbered_dirs(dir: &Path) -> Result<Vec<PathBuf>, ChumError> {
+    let mut dirs = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_digit())
+            && entry.file_type()?.is_dir()
+        {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+impl FsCleaner {
+    pub fn new(target: &str) -> FsCleaner {
+        FsCleaner {
+            target: PathBuf::from(target),
+        }
+    }
+
+    /* The shard directories, which hold the objects. */
+    fn shard_dirs(&self) -> Result<Vec<PathBuf>, ChumError> {
+        let mut shards = Vec::new();
+        for year in numbered_dirs(&self.target)? {
+            for day in numbered_dirs(&year)? {
+                shards.extend(numbered_dirs(&day)?);
+            }
+        }
+        Ok(shards)
+    }
+}
+
+impl Cleaner for FsCleaner {
+    fn list(
+        &mut self,
+        found: &mut dyn FnMut(Vec<String>) -> bool,
+    ) -> Result<(), ChumError> {
+        for shard in self.shard_dirs()? {
+            let mut batch = Vec::new();
+            for entry in std::fs::read_dir(&shard)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if Uuid::parse_str(&name).is_ok()
+                    && entry.file_type()?.is_file()
+                {
+                    batch.push(entry.path().to_string_lossy().to_string());
+                }
+                if batch.len() == BATCH_SIZE
+                    && !found(std::mem::take(&mut batch))
+                {
+                    return Ok(());
+                }
+            }
+            if !batch.is_empty() && !found(batch) {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /* Objects that are already gone don't count as errors. */
+    fn delete(&mut self, objs: &[String]) -> Vec<ChumError> {
+        objs.iter()
+            .filter_map(|obj| match std::fs::remove_file(obj) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Some(
+                    ChumError::new(&format!("Deleting {} failed: {}", obj, e)),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /* Remove the directories of the tree that are now empty. */
+    fn finish(&mut self) -> Result<(), ChumError> {
+        for year in numbered_dirs(&self.target)? {
+            for day in numbered_dirs(&year)? {
+                for shard in numbered_dirs(&day)? {
+                    let _ = std::fs::remove_dir(shard);
+                }
+                let _ = std::fs::remove_dir(day);
+            }
+            let _ = std::fs::remove_dir(year);
+        }
+        Ok(())
+    }
+}
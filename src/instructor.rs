@@ -0,0 +1,344 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use serde_json::{json, Value};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{self, Config};
+use crate::pupil::{self, msg_type, recv, send, LOCAL_ONLY};
+use crate::utils::*;
+use crate::worker::{Operation, WorkerStat};
+
+/* How often the instructor checks whether the user interrupted the run. */
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/* Per-pupil stats are labeled with the pupil's address. */
+#[derive(PartialEq, Eq, Hash)]
+struct PupilId(String);
+
+impl fmt::Debug for PupilId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/* What the thread reading from each pupil hears, tagged with its index. */
+enum Event {
+    Tick(usize, u64, TickReport),
+    Done(usize, bool), /* the pupil's run was interrupted */
+    Lost(usize, ChumError),
+}
+
+fn pupil_err(addr: &str, e: &dyn fmt::Display) -> Box<dyn Error> {
+    Box::new(ChumError::new(&format!("pupil {}: {}", addr, e)))
+}
+
+/* Wait for a pupil to accept the run. */
+fn await_ready(
+    addr: &str,
+    reader: &mut BufReader<TcpStream>,
+) -> Result<(), Box<dyn Error>> {
+    match recv(reader) {
+        Ok(Some(ref msg)) if msg_type(msg) == "ready" => Ok(()),
+        Ok(Some(ref msg)) if msg_type(msg) == "error" => Err(pupil_err(
+            addr,
+            &msg["msg"].as_str().unwrap_or("unknown error"),
+        )),
+        Ok(Some(_)) => Err(pupil_err(addr, &"unexpected message")),
+        Ok(None) => Err(pupil_err(addr, &"connection closed")),
+        Err(e) => Err(pupil_err(addr, &e)),
+    }
+}
+
+/* Pass along everything a pupil sends until its run is over. */
+fn listen(index: usize, mut reader: BufReader<TcpStream>, tx: Sender<Event>) {
+    loop {
+        let event = match recv(&mut reader) {
+            Ok(Some(msg)) => match msg_type(&msg) {
+                "tick" => match pupil::tick_from_json(&msg) {
+                    Ok((tick, report)) => Event::Tick(index, tick, report),
+                    Err(e) => Event::Lost(index, e),
+                },
+                "done" => Event::Done(
+                    index,
+                    msg["interrupted"].as_bool().unwrap_or(false),
+                ),
+                _ => Event::Lost(index, ChumError::new("unexpected message")),
+            },
+            Ok(None) => Event::Lost(index, ChumError::new("connection closed")),
+            Err(e) => Event::Lost(index, e),
+        };
+
+        let last = !matches!(event, Event::Tick(..));
+        if tx.send(event).is_err() || last {
+            return;
+        }
+    }
+}
+
+/*
+ * Combine the stats that the pupils sent for a tick and print them. Returns
+ * the start of the run proper, as seen by the pupil that has been running the
+ * longest.
+ */
+fn report_tick(
+    pupils: &[&str],
+    reports: HashMap<usize, TickReport>,
+    format: &OutputFormat,
+    op_agg: &mut HashMap<Operation, WorkerStat>,
+) -> SystemTime {
+    let warming = reports.values().any(|r| r.warmup);
+    let elapsed = reports
+        .values()
+        .map(|r| r.elapsed)
+        .max()
+        .unwrap_or_default();
+    let now = SystemTime::now();
+    let start_time = now.checked_sub(elapsed).unwrap_or(now);
+
+    let mut op_stats: HashMap<Operation, HashMap<PupilId, WorkerStat>> =
+        HashMap::new();
+    for (i, report) in reports {
+        for (op, stat) in report.ops {
+            op_stats
+                .entry(op)
+                .or_default()
+                .insert(PupilId(pupils[i].to_string()), stat);
+        }
+    }
+
+    let op_ticks = merge_tick(&op_stats, op_agg, warming);
+    print_tick(
+        start_time, format, DEF_STAGE, warming, op_stats, op_ticks, op_agg,
+    );
+    start_time
+}
+
+/*
+ * The settings sent to a pupil. Pupils given the same seed would write objects
+ * with the same names, so each one gets its own, counting up from the user's.
+ */
+fn pupil_config(config: &Config, index: usize) -> Config {
+    let mut config = config.clone();
+    config.remove("pupils");
+
+    let seed = match config.get("seed") {
+        Some(Value::String(s)) => s.parse::<u64>().ok(),
+        Some(v) => v.as_u64(),
+        None => None,
+    };
+    if let Some(seed) = seed {
+        let seed = seed.wrapping_add(index as u64);
+        config.insert("seed".to_string(), json!(seed));
+    }
+    config
+}
+
+/*
+ * Run a workload on every pupil at once and print their combined stats, which
+ * are returned too. Each pupil applies the caps on its own. Every pupil has to
+ * accept the run before any of them start it.
+ */
+pub fn instruct(
+    pupils: &[&str],
+    protocol: &str,
+    config: &Config,
+    format: &OutputFormat,
+    header: bool,
+    interrupted: &AtomicBool,
+) -> Result<RunStats, Box<dyn Error>> {
+    for name in LOCAL_ONLY.iter() {
+        if config.contains_key(*name) {
+            return Err(Box::new(ChumError::new(&format!(
+                "--{} can't be used with an instructor",
+                name
+            ))));
+        }
+    }
+
+    let mut streams = Vec::new();
+    for (i, addr) in pupils.iter().enumerate() {
+        let run = json!({
+            "type": "run",
+            "protocol": protocol,
+            "config": pupil_config(config, i),
+        });
+        let mut stream =
+            TcpStream::connect(addr).map_err(|e| pupil_err(addr, &e))?;
+        send(&mut stream, &run).map_err(|e| pupil_err(addr, &e))?;
+        streams.push(stream);
+    }
+    let mut readers = Vec::new();
+    for (addr, stream) in pupils.iter().zip(streams.iter()) {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        await_ready(addr, &mut reader)?;
+        readers.push(reader);
+    }
+
+    config::print_config(format, header, config);
+    if header
+        && (*format == OutputFormat::Tabular || *format == OutputFormat::Csv)
+    {
+        print_tabular_header(format);
+    }
+
+    for (addr, stream) in pupils.iter().zip(streams.iter_mut()) {
+        send(stream, &json!({ "type": "start" }))
+            .map_err(|e| pupil_err(addr, &e))?;
+    }
+    let mut start_time = SystemTime::now();
+
+    let (tx, rx) = channel();
+    for (i, reader) in readers.into_iter().enumerate() {
+        let tx = tx.clone();
+        thread::spawn(move || listen(i, reader, tx));
+    }
+    drop(tx);
+
+    /*
+     * Pupils tick independently, so hold on to each tick until every pupil
+     * that's still running has sent it. Once a pupil is done the remaining
+     * ticks go ahead without it.
+     */
+    let mut active: HashSet<usize> = (0..pupils.len()).collect();
+    let mut pending: BTreeMap<u64, HashMap<usize, TickReport>> =
+        BTreeMap::new();
+    let mut op_agg = HashMap::new();
+    let mut was_interrupted = false;
+    let mut stopping = false;
+    let mut lost = Vec::new();
+
+    while !active.is_empty() {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Event::Tick(i, tick, report)) => {
+                pending.entry(tick).or_default().insert(i, report);
+            }
+            Ok(Event::Done(i, pupil_interrupted)) => {
+                active.remove(&i);
+                was_interrupted |= pupil_interrupted;
+            }
+            Ok(Event::Lost(i, e)) => {
+                eprintln!("lost pupil {}: {}", pupils[i], e);
+                active.remove(&i);
+                lost.push(pupils[i]);
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if !stopping && interrupted.load(Ordering::SeqCst) {
+            stopping = true;
+            was_interrupted = true;
+            for stream in streams.iter_mut() {
+                let _ = send(stream, &json!({ "type": "stop" }));
+            }
+        }
+
+        while let Some(tick) = pending.keys().next().cloned() {
+            if !active.iter().all(|i| pending[&tick].contains_key(i)) {
+                break;
+            }
+            let reports = pending.remove(&tick).unwrap();
+            start_time = report_tick(pupils, reports, format, &mut op_agg);
+        }
+    }
+    for (_, reports) in pending {
+        start_time = report_tick(pupils, reports, format, &mut op_agg);
+    }
+
+    let mut stats = RunStats {
+        start_time,
+        elapsed: start_time.elapsed().unwrap_or_default(),
+        ops: op_agg,
+        interrupted: was_interrupted,
+        incomplete: !lost.is_empty(),
+    };
+    print_summary(format, &mut stats);
+
+    if !lost.is_empty() {
+        return Err(Box::new(ChumError::new(&format!(
+            "the run is incomplete, lost pupils: {}",
+            lost.join(", ")
+        ))));
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cleanup::Cleaner;
+    use crate::fs::FsCleaner;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_two_pupils() -> Result<(), Box<dyn Error>> {
+        let target = std::env::temp_dir()
+            .join(format!("chum-instructor-test-{}", std::process::id()));
+        std::fs::create_dir_all(&target)?;
+
+        let mut addrs = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            addrs.push(listener.local_addr()?.to_string());
+            thread::spawn(move || {
+                let _ = pupil::attend(listener, true);
+            });
+        }
+        let pupils: Vec<&str> = addrs.iter().map(String::as_str).collect();
+
+        let mut config = Config::new();
+        config.insert("target".to_string(), json!(target.to_string_lossy()));
+        config.insert("workload".to_string(), json!("w"));
+        config.insert("distribution".to_string(), json!("4k,8k,16k"));
+        config.insert("concurrency".to_string(), json!(2));
+        config.insert("max-ops".to_string(), json!(25));
+        config.insert("interval".to_string(), json!(1));
+
+        let stats = instruct(
+            &pupils,
+            "fs",
+            &config,
+            &OutputFormat::Json,
+            false,
+            &AtomicBool::new(false),
+        )?;
+        assert!(!stats.incomplete);
+
+        /*
+         * Each pupil wrote exactly its own 25 objects, and the combined totals
+         * account for every byte they left in the target.
+         */
+        let mut written = Vec::new();
+        FsCleaner::new(&target.to_string_lossy()).list(&mut |batch| {
+            written.extend(batch);
+            true
+        })?;
+        let mut bytes = 0;
+        for path in written.iter() {
+            bytes += std::fs::metadata(path)?.len();
+        }
+        let writes = &stats.ops[&Operation::Write];
+        assert_eq!(written.len(), 2 * 25);
+        assert_eq!(writes.objs, 2 * 25);
+        assert_eq!(writes.data, bytes);
+        assert!(!stats.ops.contains_key(&Operation::Error));
+
+        std::fs::remove_dir_all(&target)?;
+        Ok(())
+    }
+}
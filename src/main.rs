@@ -6,23 +6,46 @@
  * Copyright 2020 Joyent, Inc.
  */
 
+mod budget;
+mod cleanup;
+mod config;
+mod distribution;
 mod fs;
+mod instructor;
+mod phase;
+mod pupil;
 mod queue;
+mod ramp;
+mod rate;
+mod record;
 mod s3;
 mod state;
 mod utils;
+mod verify;
 mod webdav;
 mod worker;
 
-use crate::queue::{Queue, QueueMode};
+use crate::budget::OpBudget;
+use crate::config::Config;
+use crate::distribution::SizeDistribution;
+use crate::phase::Phase;
+use crate::pupil::DEF_LISTEN;
+use crate::queue::{QueueMode, ShardedQueue, DEF_QUEUE_CAP};
+use crate::ramp::RampOptions;
+use crate::rate::{Arrival, Pacer, Rate};
+use crate::record::ObjectRecord;
 use crate::utils::*;
 use crate::worker::*;
 
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc::channel, mpsc::Sender, Arc, Mutex};
+use std::time::Duration;
 use std::{thread, thread::JoinHandle};
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 
 /* Default values. */
 const DEF_CONCURRENCY: &str = "1";
@@ -30,8 +53,12 @@ const DEF_SLEEP: &str = "0";
 const DEF_DISTR: &str = "128k,256k,512k";
 const DEF_INTERVAL: &str = "2";
 const DEF_QUEUE_MODE: QueueMode = QueueMode::Rand;
+const DEF_ARRIVAL: Arrival = Arrival::Constant;
+const DEF_RAMP_HOLD: &str = "60s";
+const DEF_RAMP_PLATEAU: &str = "5";
 const DEF_WORKLOAD: &str = "r,w";
 const DEF_OUTPUT_FORMAT: &str = "h";
+const DEF_CLEANUP_CONCURRENCY: &str = "8";
 
 /*
  * Arguments specific to the 'fs' worker subcommand.
@@ -56,12 +83,17 @@ fn get_webdav_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
     vec![Arg::with_name("http2").help("use HTTP/2").long("http2")]
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    /*
-     * Options shared by all worker backends.
-     */
-    let shared_args =
-        vec!(
+/*
+ * Options shared by all worker backends.
+ */
+fn get_shared_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec!(
+
+        Arg::with_name("config")
+            .help("path to a JSON file of settings, keyed by long flag name, \
+                  flags on the command line take precedence, default: none")
+            .long("config")
+            .takes_value(true),
 
         Arg::with_name("target")
             .help("target address (webdav, s3), or path (fs) for system under \
@@ -69,7 +101,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             .takes_value(true)
             .long("target")
             .short("t")
-            .required(true),
+            .required_unless("config"),
 
         Arg::with_name("concurrency")
             .help("number of worker threads, default: 1")
@@ -83,8 +115,27 @@ fn main() -> Result<(), Box<dyn Error>> {
             .short("s")
             .takes_value(true),
 
+        Arg::with_name("rate")
+            .help("target load shared by all worker threads, in ops/s or \
+                  bytes/s (e.g. '2000ops/s', '500MB/s'), default: none \
+                  (each thread runs as fast as it can)")
+            .long("rate")
+            .takes_value(true)
+            .conflicts_with("sleep"),
+
+        Arg::with_name("arrival")
+            .help("how operations are spaced out when a rate is given: \
+                  constant or poisson, default: constant")
+            .long("arrival")
+            .takes_value(true)
+            .requires("rate"),
+
         Arg::with_name("distribution")
-            .help("comma-separated distribution of file sizes to upload, \
+            .help("distribution of file sizes to upload: a comma-separated \
+                    list of sizes and size ranges (e.g. '4k-1m'), or \
+                    lognormal:median:sigma, pareto:min:shape, or \
+                    normal:mean:stddev with an optional ':max', or \
+                    @file to load a histogram of sizes, \
                     default: 128k,256k,512k")
             .long("distribution")
             .short("d")
@@ -108,6 +159,11 @@ fn main() -> Result<(), Box<dyn Error>> {
             .short("f")
             .takes_value(true),
 
+        Arg::with_name("header")
+            .help("print a header naming the columns of tabular and CSV \
+                  output")
+            .long("header"),
+
         Arg::with_name("max-data")
             .help("maximum amount of data to write to the target, '0' disables \
                   cap, default: none")
@@ -115,6 +171,96 @@ fn main() -> Result<(), Box<dyn Error>> {
             .short("m")
             .takes_value(true),
 
+        Arg::with_name("duration")
+            .help("maximum wall-clock time to run (e.g. '90s', '30m', '2h'), \
+                  default: none")
+            .long("duration")
+            .takes_value(true),
+
+        Arg::with_name("warmup")
+            .help("time to run before collecting stats (e.g. '60s'), \
+                  excluded from the totals and summary, default: none")
+            .long("warmup")
+            .takes_value(true),
+
+        Arg::with_name("max-ops")
+            .help("maximum number of operations to perform, either in total \
+                  (e.g. '1000000') or per operation (e.g. 'r:500,w:100'), \
+                  default: none")
+            .long("max-ops")
+            .takes_value(true),
+
+        Arg::with_name("ramp")
+            .help("run a step for each of a comma-separated list of worker \
+                  thread counts (e.g. '1,2,4,...,256'), stopping once \
+                  throughput plateaus, default: none")
+            .long("ramp")
+            .takes_value(true)
+            .conflicts_with_all(&["concurrency", "duration", "max-ops",
+                "max-data"]),
+
+        Arg::with_name("ramp-hold")
+            .help("how long to run each ramp step, default: 60s")
+            .long("ramp-hold")
+            .takes_value(true)
+            .requires("ramp"),
+
+        Arg::with_name("ramp-plateau")
+            .help("percent improvement in throughput over the best step so \
+                  far needed to continue the ramp, default: 5")
+            .long("ramp-plateau")
+            .takes_value(true)
+            .requires("ramp"),
+
+        Arg::with_name("slo")
+            .help("latency objective that ends the ramp when a step misses \
+                  it (e.g. 'p99:50ms'), default: none")
+            .long("slo")
+            .takes_value(true)
+            .requires("ramp"),
+
+        Arg::with_name("phases")
+            .help("path to a JSON workload file describing phases to run \
+                  one after another, default: none")
+            .long("phases")
+            .takes_value(true)
+            .conflicts_with_all(&["ramp", "duration", "max-ops",
+                "max-data"]),
+
+        Arg::with_name("prefill")
+            .help("number of objects (e.g. '100000') or amount of data \
+                  (e.g. '50GB') to write before the workload starts, giving \
+                  reads a working set, default: none")
+            .long("prefill")
+            .takes_value(true),
+
+        Arg::with_name("prefill-concurrency")
+            .help("number of worker threads for the prefill, default: the \
+                  same as --concurrency")
+            .long("prefill-concurrency")
+            .takes_value(true)
+            .requires("prefill"),
+
+        Arg::with_name("queue-mode")
+            .help("how objects are chosen for reads and deletes: lru, mru, \
+                  rand, zipf[:skew], or hotset[:reads%:objects%], \
+                  default: rand")
+            .long("queue-mode")
+            .takes_value(true),
+
+        Arg::with_name("queue-cap")
+            .help("most objects to keep for reads and deletes, after which \
+                  objects are dropped as others are written, \
+                  default: 1000000")
+            .long("queue-cap")
+            .takes_value(true),
+
+        Arg::with_name("min-age")
+            .help("only read and delete objects written at least this long \
+                  ago (e.g. '10m'), default: none")
+            .long("min-age")
+            .takes_value(true),
+
         Arg::with_name("read-list")
             .help("path to a file listing files to read from server, default: \
                   none (files are chosen from recent uploads)")
@@ -122,44 +268,239 @@ fn main() -> Result<(), Box<dyn Error>> {
             .short("r")
             .takes_value(true),
 
+        Arg::with_name("write-list")
+            .help("path to a file to list every object written in, in the \
+                  format that --read-list reads, default: none")
+            .long("write-list")
+            .takes_value(true),
+
+        Arg::with_name("verify")
+            .help("write verifiable data and check it when reading objects \
+                  back, counting corrupt, truncated and misdirected reads")
+            .long("verify"),
+
+        Arg::with_name("seed")
+            .help("seed for every random choice the workers make, so a run \
+                  can be repeated, default: none (a new seed every run)")
+            .long("seed")
+            .takes_value(true),
+
         Arg::with_name("debug")
-            .help("enable verbose statemap tracing (may impact performance) \
-                    Must be used with the -m flag")
+            .help("enable verbose statemap tracing (may impact performance)")
             .long("debug")
             .short("D")
-    );
+    )
+}
 
-    let mut worker =
-        SubCommand::with_name("worker").about("standalone chum worker");
+/*
+ * Arguments specific to the 'instructor' subcommand. These are given after
+ * the protocol, alongside the worker arguments, so they can be kept in a config
+ * file too.
+ */
+fn get_instructor_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![Arg::with_name("pupils")
+        .help("comma-separated list of pupil addresses (e.g. \
+              'host1:7900,host2:7900') to run the workload on")
+        .long("pupils")
+        .takes_value(true)
+        .required_unless("config")]
+}
 
+/*
+ * Arguments for the 'cleanup' subcommand, which are the same for every
+ * protocol.
+ */
+fn get_cleanup_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("target")
+            .help("target address (webdav, s3), or path (fs) to delete \
+                  everything chum wrote from")
+            .takes_value(true)
+            .long("target")
+            .short("t")
+            .required(true),
+        Arg::with_name("concurrency")
+            .help("number of threads deleting objects, default: 8")
+            .long("concurrency")
+            .short("c")
+            .takes_value(true),
+        Arg::with_name("interval")
+            .help("interval in seconds at which to report progress, \
+                  default: 2")
+            .long("interval")
+            .short("i")
+            .takes_value(true),
+        Arg::with_name("format")
+            .help("progress output format, h (human readable) or j (JSON \
+                  Lines), default: h")
+            .long("format")
+            .short("f")
+            .takes_value(true),
+    ]
+}
+
+fn get_args<'a, 'b>(mode: &str, protocol: &str) -> Vec<Arg<'a, 'b>> {
+    if mode == "cleanup" {
+        return get_cleanup_args();
+    }
+
+    let mut args = get_shared_args();
+    match protocol {
+        "webdav" => args.extend(get_webdav_args()),
+        "fs" => args.extend(get_fs_args()),
+        _ => (),
+    }
+    if mode == "instructor" {
+        args.extend(get_instructor_args());
+    }
+    args
+}
+
+/*
+ * The name of every setting, which is also its long flag and its key in a
+ * config file. clap 2 can't list the arguments of a subcommand, so they're
+ * listed here too. Settings that a subcommand doesn't have are never present
+ * in its matches.
+ */
+const SETTINGS: [&str; 34] = [
+    "config",
+    "target",
+    "concurrency",
+    "sleep",
+    "rate",
+    "arrival",
+    "distribution",
+    "interval",
+    "workload",
+    "format",
+    "header",
+    "max-data",
+    "duration",
+    "warmup",
+    "max-ops",
+    "ramp",
+    "ramp-hold",
+    "ramp-plateau",
+    "slo",
+    "phases",
+    "prefill",
+    "prefill-concurrency",
+    "queue-mode",
+    "queue-cap",
+    "min-age",
+    "read-list",
+    "write-list",
+    "verify",
+    "seed",
+    "debug",
+    "percentage",
+    "no-sync",
+    "http2",
+    "pupils",
+];
+
+/*
+ * The values used for settings that were left out, for printing the settings
+ * of a run. Defaults that only apply along with another setting are only
+ * included with it.
+ */
+fn setting_defaults(
+    mode: &str,
+    matches: &ArgMatches,
+) -> Vec<(&'static str, String)> {
+    if mode == "cleanup" {
+        return vec![
+            ("concurrency", DEF_CLEANUP_CONCURRENCY.to_string()),
+            ("interval", DEF_INTERVAL.to_string()),
+            ("format", DEF_OUTPUT_FORMAT.to_string()),
+        ];
+    }
+
+    let mut defaults = vec![
+        ("sleep", DEF_SLEEP.to_string()),
+        ("distribution", DEF_DISTR.to_string()),
+        ("interval", DEF_INTERVAL.to_string()),
+        ("workload", DEF_WORKLOAD.to_string()),
+        ("format", DEF_OUTPUT_FORMAT.to_string()),
+        ("queue-mode", DEF_QUEUE_MODE.to_string()),
+        ("queue-cap", DEF_QUEUE_CAP.to_string()),
+    ];
+    if matches.is_present("rate") {
+        defaults.push(("arrival", DEF_ARRIVAL.to_string()));
+    }
+    if matches.is_present("ramp") {
+        defaults.push(("ramp-hold", DEF_RAMP_HOLD.to_string()));
+        defaults.push(("ramp-plateau", DEF_RAMP_PLATEAU.to_string()));
+    } else {
+        defaults.push(("concurrency", DEF_CONCURRENCY.to_string()));
+    }
+    defaults
+}
+
+/*
+ * A subcommand for each protocol, for the 'worker', 'instructor', or 'cleanup'
+ * mode.
+ */
+fn protocol_subcommands<'a, 'b>(mode: &str) -> Vec<App<'a, 'b>> {
     let webdav = SubCommand::with_name("webdav")
         .about("webdav mode")
-        .args(&shared_args)
-        .args(&get_webdav_args());
+        .args(&get_args(mode, "webdav"));
 
     let s3 = SubCommand::with_name("s3")
         .about("s3 mode")
-        .args(&shared_args);
+        .args(&get_args(mode, "s3"));
 
     let fs = SubCommand::with_name("fs")
         .about("local filesystem mode")
-        .args(&shared_args)
-        .args(&get_fs_args());
+        .args(&get_args(mode, "fs"));
 
-    worker = worker.subcommand(webdav);
-    worker = worker.subcommand(s3);
-    worker = worker.subcommand(fs);
+    vec![webdav, s3, fs]
+}
 
-    let matches = App::new("manta-chum")
+fn cli<'a, 'b>() -> App<'a, 'b> {
+    let worker = SubCommand::with_name("worker")
+        .about("standalone chum worker")
+        .subcommands(protocol_subcommands("worker"));
+
+    let instructor = SubCommand::with_name("instructor")
+        .about("run a workload on several pupils and combine their stats")
+        .subcommands(protocol_subcommands("instructor"));
+
+    let cleanup = SubCommand::with_name("cleanup")
+        .about("delete everything chum wrote to a target")
+        .subcommands(protocol_subcommands("cleanup"));
+
+    let pupil = SubCommand::with_name("pupil")
+        .about("run workloads handed out by an instructor")
+        .arg(
+            Arg::with_name("listen")
+                .help("address to listen for an instructor on, default: \
+                      127.0.0.1:7900")
+                .long("listen")
+                .short("l")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("allow-paths")
+                .help("accept settings that name files on this host from the \
+                      instructor (an fs target, read and write lists, and \
+                      distributions loaded from a file)")
+                .long("allow-paths"),
+        );
+
+    App::new("manta-chum")
         .about("cross-protocol storage testing tool")
         .subcommand(worker)
-        /*
-         * .subcommand(instructor)
-         * .subcommand(pupil)
-         */
-        .get_matches();
+        .subcommand(instructor)
+        .subcommand(cleanup)
+        .subcommand(pupil)
+}
 
-    let (_mode_of_operation, mode_args) = matches.subcommand();
+/* The mode of operation, the protocol subcommand, and its arguments. */
+fn protocol_matches<'a>(
+    matches: &'a ArgMatches<'a>,
+) -> (&'a str, &'a str, &'a ArgMatches<'a>) {
+    let (mode_of_operation, mode_args) = matches.subcommand();
 
     let mode_args = mode_args.unwrap_or_else(|| {
         println!("no mode of operation specified\n\n{}", matches.usage());
@@ -172,15 +513,148 @@ fn main() -> Result<(), Box<dyn Error>> {
         std::process::exit(1);
     });
 
+    (mode_of_operation, protocol_name, protocol_args)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut matches = cli().get_matches();
+
+    if let ("pupil", Some(pupil_args)) = matches.subcommand() {
+        let listen = pupil_args.value_of("listen").unwrap_or(DEF_LISTEN);
+        return pupil::serve(listen, pupil_args.is_present("allow-paths"));
+    }
+
+    /*
+     * Settings from a config file are added to the end of the command line,
+     * where they go to the protocol subcommand, and the whole thing is parsed
+     * again. Settings that were given on the command line are left out.
+     */
+    let (_, _, protocol_args) = protocol_matches(&matches);
+    if let Some(path) = protocol_args.value_of("config") {
+        let config = config::load_config(path)?;
+        let config_args = config::config_args(&config, |name| {
+            protocol_args.occurrences_of(name) > 0
+        })?;
+
+        let mut argv: Vec<String> = std::env::args().collect();
+        argv.extend(config_args);
+        matches = cli().get_matches_from(argv);
+    }
+
+    let (mode, protocol_name, protocol_args) = protocol_matches(&matches);
+
+    let effective_config = config::effective_config(
+        protocol_args,
+        &SETTINGS,
+        &setting_defaults(mode, protocol_args),
+    );
+
+    /*
+     * Stop the run cleanly on SIGINT or SIGTERM. The stat collector notices
+     * the flag, tells the workers to stop, and a summary is printed once
+     * they've exited. A second signal exits immediately.
+     */
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let sig_interrupted = interrupted.clone();
+    ctrlc::set_handler(move || {
+        if sig_interrupted.swap(true, Ordering::SeqCst) {
+            std::process::exit(1);
+        }
+        eprintln!("stopping, send the signal again to exit immediately");
+    })?;
+
+    if mode == "cleanup" {
+        let conc = protocol_args
+            .value_of("concurrency")
+            .unwrap_or(DEF_CLEANUP_CONCURRENCY)
+            .parse::<u32>()
+            .ok()
+            .filter(|c| *c > 0)
+            .ok_or_else(|| {
+                ChumError::new("concurrency should be a positive number")
+            })?;
+        let interval = protocol_args
+            .value_of("interval")
+            .unwrap_or(DEF_INTERVAL)
+            .parse::<u64>()
+            .ok()
+            .filter(|i| *i > 0)
+            .ok_or_else(|| {
+                ChumError::new("interval should be a positive number")
+            })?;
+        let format = output_format(protocol_args)?;
+        if format == OutputFormat::Tabular || format == OutputFormat::Csv {
+            return Err(Box::new(ChumError::new(
+                "cleanup progress can only be human readable or JSON",
+            )));
+        }
+        return cleanup::cleanup(
+            protocol_name,
+            protocol_args
+                .value_of("target")
+                .expect("target is required"),
+            conc,
+            Duration::from_secs(interval),
+            &format,
+            &interrupted,
+        );
+    }
+
+    if mode == "instructor" {
+        let pupils: Vec<&str> = protocol_args
+            .value_of("pupils")
+            .ok_or_else(|| {
+                ChumError::new(
+                    "pupils are required, with --pupils or in the config file",
+                )
+            })?
+            .split(',')
+            .collect();
+        instructor::instruct(
+            &pupils,
+            protocol_name,
+            &effective_config,
+            &output_format(protocol_args)?,
+            protocol_args.is_present("header"),
+            &interrupted,
+        )?;
+        return Ok(());
+    }
+
+    let plan = plan_run(protocol_name, protocol_args)?;
+    execute(plan, &effective_config, &interrupted);
+
+    Ok(())
+}
+
+fn output_format(args: &ArgMatches) -> Result<OutputFormat, ChumError> {
+    args.value_of("format").unwrap_or(DEF_OUTPUT_FORMAT).parse()
+}
+
+/* Everything needed to start a run, parsed from the command line. */
+struct RunPlan {
+    workeropts: WorkerOptions,
+    sopts: StatOptions,
+    conc: u32,
+    caps: Vec<DataCap>,
+    ramp_opts: Option<RampOptions>,
+    phases: Option<Vec<Phase>>,
+    prefill: Option<Phase>,
+    header: bool,
+    smap_thread: Option<JoinHandle<()>>,
+    list_thread: Option<JoinHandle<()>>,
+}
+
+fn plan_run(
+    protocol_name: &str,
+    protocol_args: &ArgMatches,
+) -> Result<RunPlan, Box<dyn Error>> {
     /*
      * Get args with sensible defaults.
      */
     let distr = protocol_args.value_of("distribution").unwrap_or(DEF_DISTR);
     let workload = protocol_args.value_of("workload").unwrap_or(DEF_WORKLOAD);
-    let format: OutputFormat = protocol_args
-        .value_of("format")
-        .unwrap_or(DEF_OUTPUT_FORMAT)
-        .parse()?;
+    let format = output_format(protocol_args)?;
 
     let conc = protocol_args
         .value_of("concurrency")
@@ -201,68 +675,195 @@ fn main() -> Result<(), Box<dyn Error>> {
     /*
      * Get args with no defaults.
      */
-    let target = protocol_args.value_of("target").unwrap();
+    let target = protocol_args.value_of("target").ok_or_else(|| {
+        ChumError::new("a target is required, with -t or in the config file")
+    })?;
 
     let readlist = protocol_args.value_of("read-list");
 
     /* Some arguments require more advanced parsing. */
+    let mut caps = Vec::new();
+
     /* Prefer percentage data cap, otherwise use the bytes-written data cap. */
-    let cap = match protocol_args.value_of("percentage") {
+    match protocol_args.value_of("percentage") {
         Some(p) => {
             let capnum = p
                 .parse::<u32>()
                 .expect("percentage should be a positive number");
-            Some(DataCap::Percentage(capnum))
+            caps.push(DataCap::Percentage(capnum));
         }
-        None => match protocol_args.value_of("max-data") {
-            Some(m) => {
+        None => {
+            if let Some(m) = protocol_args.value_of("max-data") {
                 let capnum = parse_human(&m)?;
-                Some(DataCap::LogicalData(capnum))
+                caps.push(DataCap::LogicalData(capnum));
             }
-            None => None,
-        },
-    };
+        }
+    }
+
+    /* The time cap can be used alongside either of the data caps. */
+    if let Some(d) = protocol_args.value_of("duration") {
+        caps.push(DataCap::Duration(parse_duration(d)?));
+    }
 
-    let distr = convert_numeric_distribution(expand_distribution(&distr)?)?;
+    if let Some(o) = protocol_args.value_of("max-ops") {
+        caps.push(DataCap::Operations(parse_operation_caps(o)?));
+    }
+
+    let distr: SizeDistribution = distr.parse()?;
     let ops = convert_operation_distribution(expand_distribution(&workload)?)?;
+    check_operation_caps(&caps, &ops)?;
+
+    let qmode = match protocol_args.value_of("queue-mode") {
+        Some(m) => m.parse()?,
+        None => DEF_QUEUE_MODE,
+    };
+
+    /*
+     * With a seed, every generator in the run is seeded from this one, in the
+     * order that they're created.
+     */
+    let seed = match protocol_args.value_of("seed") {
+        Some(s) => Some(s.parse::<u64>().map_err(|_| {
+            ChumError::new("seed should be a non-negative integer")
+        })?),
+        None => None,
+    };
+    let seeder = seed.map(|s| Arc::new(Mutex::new(StdRng::seed_from_u64(s))));
+
+    let pacer = match protocol_args.value_of("rate") {
+        Some(r) => {
+            let rate: Rate = r.parse()?;
+            let arrival = match protocol_args.value_of("arrival") {
+                Some(a) => a.parse()?,
+                None => DEF_ARRIVAL,
+            };
+            Some(Arc::new(Pacer::new(rate, arrival, new_rng(&seeder))))
+        }
+        None => None,
+    };
+
+    let ramp_opts = match protocol_args.value_of("ramp") {
+        Some(r) => {
+            if !caps.is_empty() {
+                return Err(Box::new(ChumError::new(
+                    "caps can't be combined with --ramp",
+                )));
+            }
+            let hold =
+                protocol_args.value_of("ramp-hold").unwrap_or(DEF_RAMP_HOLD);
+            let plateau = protocol_args
+                .value_of("ramp-plateau")
+                .unwrap_or(DEF_RAMP_PLATEAU)
+                .parse::<f64>()
+                .expect("ramp plateau should be a number");
+            let slo = match protocol_args.value_of("slo") {
+                Some(s) => Some(s.parse()?),
+                None => None,
+            };
+            Some(RampOptions {
+                steps: ramp::parse_ramp_steps(r)?,
+                hold: parse_duration(hold)?,
+                plateau,
+                slo,
+            })
+        }
+        None => None,
+    };
+
+    let prefill = match protocol_args.value_of("prefill") {
+        Some(amount) => {
+            let conc = match protocol_args.value_of("prefill-concurrency") {
+                Some(c) => Some(c.parse::<u32>().map_err(|_| {
+                    ChumError::new(
+                        "prefill concurrency should be a positive number",
+                    )
+                })?),
+                None => None,
+            };
+            Some(phase::prefill_phase(amount, conc)?)
+        }
+        None => None,
+    };
+
+    let phases = match protocol_args.value_of("phases") {
+        Some(path) => {
+            if !caps.is_empty() {
+                return Err(Box::new(ChumError::new(
+                    "caps can't be combined with --phases",
+                )));
+            }
+            let phases = phase::load_phases(path)?;
+            for p in phases.iter() {
+                let pops = p.operations.as_ref().unwrap_or(&ops);
+                check_operation_caps(&p.caps, pops).map_err(|e| {
+                    ChumError::new(&format!("phase '{}': {}", p.name, e))
+                })?;
+            }
+            Some(phases)
+        }
+        None => None,
+    };
 
-    let q: Arc<Mutex<Queue<String>>> =
-        Arc::new(Mutex::new(Queue::new(DEF_QUEUE_MODE)));
+    /*
+     * A shard per worker keeps the workers from contending for the queue, so
+     * there are as many as the most workers that the run will have at once.
+     */
+    let shards = ramp_opts
+        .iter()
+        .flat_map(|r| r.steps.iter().copied())
+        .chain(phases.iter().flatten().filter_map(|p| p.concurrency))
+        .chain(prefill.iter().filter_map(|p| p.concurrency))
+        .fold(conc, u32::max) as usize;
+    let mut queue = match protocol_args.value_of("queue-cap") {
+        Some(c) => {
+            let cap = match c.parse::<usize>() {
+                Ok(cap) if cap > 0 => cap,
+                _ => {
+                    return Err(Box::new(ChumError::new(
+                        "queue cap should be a positive number",
+                    )))
+                }
+            };
+            ShardedQueue::with_capacity(qmode, cap, shards)
+        }
+        None => ShardedQueue::new(qmode, shards),
+    };
+    if let Some(age) = protocol_args.value_of("min-age") {
+        queue.set_min_age(parse_duration(age)?);
+    }
+    let q: Arc<ShardedQueue<ObjectRecord>> = Arc::new(queue);
     let sync = !protocol_args.is_present("no-sync");
     let http2 = protocol_args.is_present("http2");
+    let verify = protocol_args.is_present("verify");
 
     let targ = target.to_string();
     let proto = protocol_name.to_string();
 
     if let Some(rl) = readlist {
-        populate_queue(q.clone(), rl.to_string())?
+        populate_queue(&q, rl.to_string(), &mut new_rng(&seeder))?
     }
 
+    let (write_list, list_thread) = match protocol_args.value_of("write-list") {
+        Some(wl) => {
+            let (tx, jh) =
+                start_write_list(wl.to_string(), protocol_name.to_string())?;
+            (Some(tx), Some(jh))
+        }
+        None => (None, None),
+    };
+
     /*
-     * Start the real work. Kick off worker threads and a stat listener.
+     * Start the real work.
      */
 
     let mut debug_tx: Option<Sender<state::State>> = None;
     let (tx, rx) = channel();
     let smap_thread = if protocol_args.is_present("debug") {
         /*
-         * The statemap format isn't a streaming format, so we need the states
-         * to stop coming (i.e. the program ends) at some point. The only ways
-         * to end the program are to:
-         * - use a data cap
-         * - send a signal
-         *
-         * I don't want to go through the signal handling dance, so a data cap
-         * is the only way to end manta-chum in a quiescent manner.
+         * The statemap format isn't a streaming format, so the states are
+         * written out once every worker has exited. That happens when a cap
+         * is reached or when the user sends a signal.
          */
-        if !protocol_args.is_present("max-data") {
-            println!(
-                "--debug must be used with -m flag\n\n{}",
-                protocol_args.usage()
-            );
-            std::process::exit(1);
-        }
-
         debug_tx = Some(tx);
         Some(thread::spawn(move || {
             state::state_listener(rx);
@@ -271,7 +872,6 @@ fn main() -> Result<(), Box<dyn Error>> {
         None
     };
 
-    let (tx, rx) = channel();
     let workeropts = WorkerOptions {
         protocol: protocol_name.to_string(),
         read_queue: ops.contains(&Operation::Read)
@@ -280,49 +880,288 @@ fn main() -> Result<(), Box<dyn Error>> {
         distribution: distr,
         target: targ.clone(),
         sleep,
-        tx,
+        pacer,
+        budget: None,
         debug_tx: debug_tx.clone(),
         queue: q,
+        verify,
+        seed,
+        seeder,
+        write_list,
         sync,
         http2,
     };
 
-    let mut worker_threads: Vec<JoinHandle<_>> = Vec::new();
-    for _ in 0..conc {
-        let wopts = workeropts.clone();
-        worker_threads.push(thread::spawn(move || {
-            Worker::new(wopts).work();
-        }));
+    let warmup = match protocol_args.value_of("warmup") {
+        Some(w) => parse_duration(w)?,
+        None => Duration::from_secs(0),
+    };
+
+    let sopts = StatOptions {
+        interval,
+        warmup,
+        report: None,
+        format,
+        target: targ,
+        protocol: proto,
+        stage: DEF_STAGE.to_string(),
+    };
+
+    Ok(RunPlan {
+        workeropts,
+        sopts,
+        conc,
+        caps,
+        ramp_opts,
+        phases,
+        prefill,
+        header: protocol_args.is_present("header"),
+        smap_thread,
+        list_thread,
+    })
+}
+
+/* Print the config, then run the workload and print its summary. */
+fn execute(plan: RunPlan, config: &Config, interrupted: &AtomicBool) {
+    let RunPlan {
+        workeropts,
+        sopts,
+        conc,
+        caps,
+        ramp_opts,
+        phases,
+        prefill,
+        header,
+        smap_thread,
+        list_thread,
+    } = plan;
+    let format = sopts.format;
+
+    config::print_config(&format, header, config);
+    if header
+        && (format == OutputFormat::Tabular || format == OutputFormat::Csv)
+    {
+        print_tabular_header(&format);
     }
 
-    /* Kick off statistics collection and reporting. */
-    let stat_thread = thread::spawn(move || {
-        collect_stats(rx, interval, format, cap, targ.clone(), proto.clone());
-    });
+    let prefilled = match &prefill {
+        Some(prefill) => {
+            run_prefill(prefill, &workeropts, conc, &sopts, interrupted)
+        }
+        None => true,
+    };
+
+    if !prefilled {
+        /* The user stopped the run during the prefill. */
+    } else if let Some(phases) = phases {
+        run_phases(&phases, &workeropts, conc, &sopts, interrupted);
+    } else if let Some(ropts) = ramp_opts {
+        ramp::ramp(&ropts, &format, |step, conc, hold| {
+            let sopts = StatOptions {
+                stage: format!("step{}", step + 1),
+                ..sopts.clone()
+            };
+            run(
+                &workeropts,
+                conc,
+                &sopts,
+                &[DataCap::Duration(hold)],
+                interrupted,
+            )
+        });
+    } else {
+        let mut stats = run(&workeropts, conc, &sopts, &caps, interrupted);
+        print_summary(&format, &mut stats);
+    }
 
     /*
-     * To make sure that the state thread exits when all worker threads exit,
-     * drop our copy of the sender channel here.
+     * To make sure that the state and write list threads exit when all worker
+     * threads exit, drop our copy of the sender channels here.
      *
-     * The state collection thread exits when all senders exit. This main
-     * thread will live the life of the program and it will not send any states
-     * through the channel.
+     * Both threads exit when all senders exit. This main thread will live the
+     * life of the program and it will not send anything through the channels.
      */
-    drop(debug_tx);
     drop(workeropts);
 
+    if let Some(jh) = smap_thread {
+        jh.join().expect("failed to join statemap thread");
+    }
+
+    if let Some(jh) = list_thread {
+        jh.join().expect("failed to join write list thread");
+    }
+}
+
+/*
+ * Run a single phase. Settings that the phase doesn't override come from the
+ * command line.
+ */
+fn run_phase(
+    phase: &Phase,
+    base: &WorkerOptions,
+    conc: u32,
+    sopts: &StatOptions,
+    interrupted: &AtomicBool,
+) -> RunStats {
+    let mut wopts = base.clone();
+
+    /* Later phases may read or delete what earlier phases wrote. */
+    wopts.read_queue = true;
+    if let Some(ops) = &phase.operations {
+        wopts.operations = ops.clone();
+    }
+    if let Some(distr) = &phase.distribution {
+        wopts.distribution = distr.clone();
+    }
+    if let Some(sleep) = phase.sleep {
+        wopts.sleep = sleep;
+    }
+    if let Some(rate) = phase.rate {
+        let arrival = phase.arrival.unwrap_or(DEF_ARRIVAL);
+        let rng = new_rng(&wopts.seeder);
+        wopts.pacer = Some(Arc::new(Pacer::new(rate, arrival, rng)));
+    }
+
+    let mut caps = phase.caps.clone();
+    if phase.drain {
+        caps.push(DataCap::Drained(wopts.queue.clone()));
+    }
+
+    run(
+        &wopts,
+        phase.concurrency.unwrap_or(conc),
+        sopts,
+        &caps,
+        interrupted,
+    )
+}
+
+/* Run each phase of a workload file in turn, printing a summary after each. */
+fn run_phases(
+    phases: &[Phase],
+    base: &WorkerOptions,
+    conc: u32,
+    sopts: &StatOptions,
+    interrupted: &AtomicBool,
+) {
+    for (i, phase) in phases.iter().enumerate() {
+        phase::print_phase(&sopts.format, i, phases.len(), &phase.name);
+        let sopts = StatOptions {
+            stage: format!("phase{}", i + 1),
+            ..sopts.clone()
+        };
+        let mut stats = run_phase(phase, base, conc, &sopts, interrupted);
+        print_summary(&sopts.format, &mut stats);
+
+        if stats.interrupted {
+            break;
+        }
+    }
+}
+
+/*
+ * Write the objects for the prefill as fast as possible, ignoring any rate
+ * or warmup given for the workload. Returns false if the user stopped the run.
+ */
+fn run_prefill(
+    prefill: &Phase,
+    base: &WorkerOptions,
+    conc: u32,
+    sopts: &StatOptions,
+    interrupted: &AtomicBool,
+) -> bool {
+    let mut wopts = base.clone();
+    wopts.pacer = None;
+    let sopts = StatOptions {
+        warmup: Duration::from_secs(0),
+        stage: "prefill".to_string(),
+        ..sopts.clone()
+    };
+
+    phase::print_prefill(&sopts.format);
+    let mut stats = run_phase(prefill, &wopts, conc, &sopts, interrupted);
+    print_summary(&sopts.format, &mut stats);
+
+    !stats.interrupted
+}
+
+/*
+ * Kick off 'conc' worker threads and collect their stats until a cap is
+ * reached or the user interrupts the run. The workers have exited by the time
+ * this returns.
+ */
+fn run(
+    wopts: &WorkerOptions,
+    conc: u32,
+    sopts: &StatOptions,
+    caps: &[DataCap],
+    interrupted: &AtomicBool,
+) -> RunStats {
+    let (tx, rx) = channel();
+    let stop = Arc::new(AtomicBool::new(false));
+
+    if let Some(pacer) = &wopts.pacer {
+        pacer.reset();
+    }
+
+    /* Operation counts start once the warmup is over. */
+    let warming = sopts.warmup > Duration::from_secs(0);
+    let budget = OpBudget::new(caps, !warming).map(Arc::new);
+
+    let mut worker_threads: Vec<JoinHandle<_>> = Vec::new();
+    for _ in 0..conc {
+        let mut wopts = wopts.clone();
+        wopts.budget = budget.clone();
+        let rng = new_rng(&wopts.seeder);
+        let tx = tx.clone();
+        let stop = stop.clone();
+        worker_threads.push(thread::spawn(move || {
+            Worker::new(wopts, rng, tx, stop).work();
+        }));
+    }
+
     /*
-     * When the stat thread exits we know that enough data was written.
+     * Drop our copy of the sender so that the channel disconnects once every
+     * worker has exited.
      */
-    stat_thread.join().expect("failed to join stat thread");
+    drop(tx);
+
+    let stats =
+        collect_stats(rx, sopts, caps, budget.as_deref(), &stop, interrupted);
 
     for hdl in worker_threads {
         hdl.join().expect("failed to join worker thread");
     }
 
-    if let Some(jh) = smap_thread {
-        jh.join().expect("failed to join statemap thread");
-    }
+    stats
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_settings() {
+        /* Every flag in the help of a protocol subcommand is a setting. */
+        let flag_re = Regex::new(r"--([a-z0-9-]+)").unwrap();
+        let mut flags = BTreeSet::new();
+        for mode in &["worker", "instructor", "cleanup"] {
+            for app in protocol_subcommands(mode) {
+                let mut help = Vec::new();
+                app.write_help(&mut help).unwrap();
+                let help = String::from_utf8(help).unwrap();
+                for caps in flag_re.captures_iter(&help) {
+                    flags.insert(caps[1].to_string());
+                }
+            }
+        }
+        flags.remove("help");
+        flags.remove("version");
+
+        let settings: BTreeSet<String> =
+            SETTINGS.iter().map(|s| s.to_string()).collect();
+        assert_eq!(flags, settings);
+    }
 }
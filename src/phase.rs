@@ -0,0 +1,445 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use serde_json::{json, Value};
+
+use std::fs::File;
+use std::io::BufReader;
+use std::str::FromStr;
+
+use crate::distribution::SizeDistribution;
+use crate::rate::{Arrival, Rate};
+use crate::utils::*;
+use crate::worker::Operation;
+
+/*
+ * A workload file describes a sequence of phases, run one after another. For
+ * example, to write 10GB, then run a read-heavy workload for half an hour,
+ * then delete everything that was written:
+ *
+ *   {
+ *     "phases": [
+ *       { "name": "prefill", "workload": "w", "max-data": "10g" },
+ *       { "name": "steady", "workload": "r:8,w:2", "duration": "30m",
+ *         "concurrency": 32, "rate": "2000ops/s" },
+ *       { "name": "cleanup", "workload": "d", "drain": true }
+ *     ]
+ *   }
+ *
+ * Phase keys are named after, and take the same values as, the command line
+ * flags. Anything a phase doesn't set is taken from the command line. Numbers
+ * can be given as JSON numbers or strings.
+ *
+ * Every phase needs a way to end: a duration, an operation count, a data cap,
+ * or 'drain', which ends the phase once every object in the queue has been
+ * deleted. The queue of written objects carries over from phase to phase.
+ */
+pub struct Phase {
+    pub name: String,
+    pub operations: Option<Vec<Operation>>,
+    pub distribution: Option<SizeDistribution>,
+    pub concurrency: Option<u32>,
+    pub sleep: Option<u64>,
+    pub rate: Option<Rate>,
+    pub arrival: Option<Arrival>,
+    pub caps: Vec<DataCap>,
+    pub drain: bool,
+}
+
+const PHASE_KEYS: [&str; 12] = [
+    "name",
+    "workload",
+    "distribution",
+    "concurrency",
+    "sleep",
+    "rate",
+    "arrival",
+    "duration",
+    "max-ops",
+    "max-data",
+    "percentage",
+    "drain",
+];
+
+/* Phase values can be strings or numbers. */
+fn value_to_string(val: &Value) -> Option<String> {
+    match val {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/* The number under 'key', which has to fit in an unsigned T. */
+fn parse_num<T: FromStr>(
+    key: &str,
+    val: Option<&str>,
+) -> Result<Option<T>, ChumError> {
+    match val {
+        Some(v) => v.parse::<T>().map(Some).map_err(|_| {
+            ChumError::new(&format!("'{}' must be a positive number", key))
+        }),
+        None => Ok(None),
+    }
+}
+
+fn parse_phase(index: usize, val: &Value) -> Result<Phase, ChumError> {
+    let obj = match val.as_object() {
+        Some(obj) => obj,
+        None => {
+            return Err(ChumError::new(&format!(
+                "phase {} must be an object",
+                index + 1
+            )))
+        }
+    };
+
+    let mut name = format!("phase {}", index + 1);
+    if let Some(n) = obj.get("name").and_then(|n| n.as_str()) {
+        name = n.to_string();
+    }
+    let phase_err =
+        |e: ChumError| ChumError::new(&format!("phase '{}': {}", name, e));
+
+    let mut strs = Vec::new();
+    for (key, val) in obj.iter() {
+        if !PHASE_KEYS.contains(&key.as_str()) {
+            return Err(phase_err(ChumError::new(&format!(
+                "unknown key '{}'",
+                key
+            ))));
+        }
+        if key == "drain" {
+            continue;
+        }
+        match value_to_string(val) {
+            Some(s) => strs.push((key.as_str(), s)),
+            None => {
+                return Err(phase_err(ChumError::new(&format!(
+                    "'{}' must be a string or a number",
+                    key
+                ))))
+            }
+        }
+    }
+    let get = |key: &str| {
+        strs.iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    };
+
+    let drain = match obj.get("drain") {
+        None => false,
+        Some(Value::Bool(b)) => *b,
+        Some(_) => {
+            return Err(phase_err(ChumError::new(
+                "'drain' must be true or false",
+            )))
+        }
+    };
+
+    let mut caps = Vec::new();
+    if let Some(p) =
+        parse_num("percentage", get("percentage")).map_err(phase_err)?
+    {
+        caps.push(DataCap::Percentage(p));
+    }
+    if let Some(m) = get("max-data") {
+        caps.push(DataCap::LogicalData(parse_human(m).map_err(phase_err)?));
+    }
+    if let Some(d) = get("duration") {
+        caps.push(DataCap::Duration(parse_duration(d).map_err(phase_err)?));
+    }
+    if let Some(o) = get("max-ops") {
+        caps.push(DataCap::Operations(
+            parse_operation_caps(o).map_err(phase_err)?,
+        ));
+    }
+    if caps.is_empty() && !drain {
+        return Err(phase_err(ChumError::new(
+            "needs a duration, max-ops, max-data, percentage, or drain",
+        )));
+    }
+
+    let concurrency =
+        parse_num("concurrency", get("concurrency")).map_err(phase_err)?;
+    if concurrency == Some(0) {
+        return Err(phase_err(ChumError::new(
+            "concurrency should be a positive number",
+        )));
+    }
+
+    let operations = match get("workload") {
+        Some(w) => Some(
+            expand_distribution(w)
+                .and_then(convert_operation_distribution)
+                .map_err(phase_err)?,
+        ),
+        None => None,
+    };
+
+    Ok(Phase {
+        name: name.clone(),
+        operations,
+        distribution: match get("distribution") {
+            Some(d) => Some(d.parse().map_err(phase_err)?),
+            None => None,
+        },
+        concurrency,
+        sleep: parse_num("sleep", get("sleep")).map_err(phase_err)?,
+        rate: match get("rate") {
+            Some(r) => Some(r.parse().map_err(phase_err)?),
+            None => None,
+        },
+        arrival: match get("arrival") {
+            Some(a) => Some(a.parse().map_err(phase_err)?),
+            None => None,
+        },
+        caps,
+        drain,
+    })
+}
+
+pub fn parse_phases(val: &Value) -> Result<Vec<Phase>, ChumError> {
+    let phases = match val.get("phases").and_then(|p| p.as_array()) {
+        Some(phases) if !phases.is_empty() => phases,
+        _ => {
+            return Err(ChumError::new(
+                "workload file must have a non-empty 'phases' list",
+            ))
+        }
+    };
+
+    let mut parsed = Vec::new();
+    for (i, phase) in phases.iter().enumerate() {
+        parsed.push(parse_phase(i, phase)?);
+    }
+    Ok(parsed)
+}
+
+pub fn load_phases(path: &str) -> Result<Vec<Phase>, ChumError> {
+    let file = File::open(path).map_err(|e| {
+        ChumError::new(&format!("failed to open workload file: {}", e))
+    })?;
+    let val: Value =
+        serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+            ChumError::new(&format!("failed to parse workload file: {}", e))
+        })?;
+    parse_phases(&val)
+}
+
+/*
+ * The prefill writes objects before the workload starts, so that reads have a
+ * working set from the beginning. 'amount' is a number of objects (e.g.
+ * '100000') or an amount of data (e.g. '50GB' or '50g').
+ */
+pub fn prefill_phase(
+    amount: &str,
+    concurrency: Option<u32>,
+) -> Result<Phase, ChumError> {
+    let bad = || {
+        ChumError::new(
+            "prefill must be a number of objects or an amount of data \
+             (e.g. '100000', '50GB')",
+        )
+    };
+
+    let cap = if !amount.is_empty()
+        && amount.chars().all(|c| c.is_ascii_digit())
+    {
+        DataCap::Operations(vec![(None, amount.parse().map_err(|_| bad())?)])
+    } else {
+        let size = amount.strip_suffix(&['b', 'B'][..]).unwrap_or(amount);
+        DataCap::LogicalData(parse_human(size).map_err(|_| bad())?)
+    };
+    match cap {
+        DataCap::Operations(ref c) if c[0].1 == 0 => return Err(bad()),
+        DataCap::LogicalData(0) => return Err(bad()),
+        _ => (),
+    }
+    if concurrency == Some(0) {
+        return Err(ChumError::new(
+            "prefill concurrency should be a positive number",
+        ));
+    }
+
+    Ok(Phase {
+        name: "prefill".to_string(),
+        operations: Some(vec![Operation::Write]),
+        distribution: None,
+        concurrency,
+        sleep: Some(0),
+        rate: None,
+        arrival: None,
+        caps: vec![cap],
+        drain: false,
+    })
+}
+
+/* Announce the prefill. It's followed by a summary, like a phase. */
+pub fn print_prefill(format: &OutputFormat) {
+    match format {
+        OutputFormat::Human | OutputFormat::HumanVerbose => {
+            println!("===");
+            println!("Prefill");
+        }
+        OutputFormat::Json | OutputFormat::JsonVerbose => println!(
+            "{}",
+            json!({
+                "type": "prefill",
+                "time": unix_time(),
+            })
+        ),
+        OutputFormat::Tabular | OutputFormat::Csv => (),
+    }
+}
+
+/* Announce the start of a phase. */
+pub fn print_phase(
+    format: &OutputFormat,
+    index: usize,
+    count: usize,
+    name: &str,
+) {
+    match format {
+        OutputFormat::Human | OutputFormat::HumanVerbose => {
+            println!("===");
+            println!("Phase {}/{} ({})", index + 1, count, name);
+        }
+        OutputFormat::Json | OutputFormat::JsonVerbose => println!(
+            "{}",
+            json!({
+                "type": "phase",
+                "time": unix_time(),
+                "phase": index + 1,
+                "phases": count,
+                "name": name,
+            })
+        ),
+        /* Tabular and CSV output have fixed columns. */
+        OutputFormat::Tabular | OutputFormat::Csv => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_phases() -> Result<(), ChumError> {
+        let val = json!({
+            "phases": [
+                { "name": "prefill", "workload": "w", "max-data": "10g",
+                  "concurrency": 16, "distribution": "1m-4m" },
+                { "workload": "r:8,w:2", "duration": 1800,
+                  "rate": "2000ops/s", "arrival": "poisson" },
+                { "name": "cleanup", "workload": "d", "drain": true }
+            ]
+        });
+        let phases = parse_phases(&val)?;
+        assert_eq!(phases.len(), 3);
+
+        assert_eq!(phases[0].name, "prefill");
+        assert_eq!(phases[0].operations, Some(vec![Operation::Write]));
+        assert_eq!(phases[0].concurrency, Some(16));
+        assert_eq!(
+            phases[0].distribution,
+            Some(SizeDistribution::List(vec![(1 << 20, 4 << 20)]))
+        );
+        match phases[0].caps.as_slice() {
+            [DataCap::LogicalData(cap)] => assert_eq!(*cap, 10 << 30),
+            _ => panic!("unexpected caps for prefill"),
+        }
+
+        assert_eq!(phases[1].name, "phase 2");
+        assert_eq!(phases[1].rate, Some(Rate::Ops(2000.0)));
+        assert_eq!(phases[1].arrival, Some(Arrival::Poisson));
+        assert_eq!(phases[1].distribution, None);
+        match phases[1].caps.as_slice() {
+            [DataCap::Duration(d)] => assert_eq!(d.as_secs(), 1800),
+            _ => panic!("unexpected caps for phase 2"),
+        }
+
+        assert!(phases[2].drain);
+        assert!(phases[2].caps.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_phases_errors() {
+        let err = |val: Value| match parse_phases(&val) {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.to_string(),
+        };
+
+        assert_eq!(
+            err(json!({ "phases": [] })),
+            "workload file must have a non-empty 'phases' list"
+        );
+        assert_eq!(
+            err(json!({ "phases": [{ "name": "a", "workload": "r" }] })),
+            "phase 'a': needs a duration, max-ops, max-data, percentage, or \
+             drain"
+        );
+        assert_eq!(
+            err(json!({ "phases": [{ "duration": "1m", "workers": 4 }] })),
+            "phase 'phase 1': unknown key 'workers'"
+        );
+        assert_eq!(
+            err(json!({ "phases": [{ "duration": "1m", "concurrency": -1 }] })),
+            "phase 'phase 1': 'concurrency' must be a positive number"
+        );
+        assert_eq!(
+            err(json!({ "phases": [{ "duration": "1m", "concurrency": 0 }] })),
+            "phase 'phase 1': concurrency should be a positive number"
+        );
+        assert_eq!(
+            err(json!({ "phases": [{ "percentage": 4_294_967_346u64 }] })),
+            "phase 'phase 1': 'percentage' must be a positive number"
+        );
+        assert_eq!(
+            err(json!({ "phases": [{ "name": "b", "duration": "0s" }] })),
+            "phase 'b': provided duration must be non-zero"
+        );
+        assert_eq!(
+            err(json!({ "phases": [{ "drain": "yes" }] })),
+            "phase 'phase 1': 'drain' must be true or false"
+        );
+    }
+
+    #[test]
+    fn test_prefill_phase() -> Result<(), ChumError> {
+        let phase = prefill_phase("100000", Some(8))?;
+        assert_eq!(phase.operations, Some(vec![Operation::Write]));
+        assert_eq!(phase.concurrency, Some(8));
+        match phase.caps.as_slice() {
+            [DataCap::Operations(c)] => assert_eq!(c, &vec![(None, 100_000)]),
+            _ => panic!("unexpected caps for a count"),
+        }
+
+        for (amount, bytes) in &[("50GB", 50 << 30), ("50g", 50 << 30)] {
+            match prefill_phase(amount, None)?.caps.as_slice() {
+                [DataCap::LogicalData(cap)] => assert_eq!(cap, bytes),
+                _ => panic!("unexpected caps for {}", amount),
+            }
+        }
+
+        for bad in &["", "0", "0g", "50x", "1.5g", "-1", "GB"] {
+            assert!(prefill_phase(bad, None).is_err(), "{}", bad);
+        }
+        assert_eq!(
+            prefill_phase("100", Some(0)).err(),
+            Some(ChumError::new(
+                "prefill concurrency should be a positive number"
+            ))
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc::channel, Arc};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{self, Config};
+use crate::utils::{ChumError, TickReport};
+use crate::worker::{Operation, WorkerStat};
+use crate::RunPlan;
+
+/*
+ * Distributed mode.
+ *
+ * A single client host often can't saturate a storage cluster. A pupil waits
+ * for an instructor to connect, runs the workload it's given, and streams the
+ * stats for each tick back. An instructor does this with several pupils at
+ * once and combines their stats into one report.
+ *
+ * Messages are JSON objects, one per line, each with a 'type':
+ *
+ *   instructor -> pupil
+ *     run    {"protocol": "s3", "config": {...}}, settings like a config file
+ *     start  start the run
+ *     stop   stop the run early
+ *
+ *   pupil -> instructor
+ *     ready  the settings were accepted, waiting for 'start'
+ *     error  {"msg": "..."}, the settings were rejected
+ *     tick   {"tick": 0, "warmup": false, "elapsed": 2.0, "ops": {...}}
+ *     done   {"interrupted": false}, the run is over
+ *
+ * Ticks carry the stats for each operation as WorkerStat::to_wire() objects.
+ * A pupil runs one workload per connection, then waits for the next one.
+ *
+ * There's no authentication, anyone who can reach a pupil can hand it a
+ * workload. Pupils listen on localhost unless told otherwise, and don't take
+ * settings that name files on their host unless started with --allow-paths.
+ */
+
+pub const DEF_LISTEN: &str = "127.0.0.1:7900";
+
+/* Settings that only make sense when chum runs on a single host. */
+pub const LOCAL_ONLY: [&str; 4] = ["ramp", "phases", "prefill", "debug"];
+
+const WIRE_OPS: [Operation; 4] = [
+    Operation::Read,
+    Operation::Write,
+    Operation::Delete,
+    Operation::Error,
+];
+
+pub fn send<W: Write>(stream: &mut W, msg: &Value) -> Result<(), ChumError> {
+    writeln!(stream, "{}", msg)
+        .map_err(|e| ChumError::new(&format!("failed to send message: {}", e)))
+}
+
+/* The next message, or None once the other end hangs up. */
+pub fn recv<R: BufRead>(reader: &mut R) -> Result<Option<Value>, ChumError> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => Ok(None),
+        Ok(_) => serde_json::from_str(&line)
+            .map(Some)
+            .map_err(|e| ChumError::new(&format!("malformed message: {}", e))),
+        Err(e) => {
+            Err(ChumError::new(&format!("failed to receive message: {}", e)))
+        }
+    }
+}
+
+pub fn msg_type(msg: &Value) -> &str {
+    msg["type"].as_str().unwrap_or("")
+}
+
+pub fn tick_to_json(tick: u64, report: &TickReport) -> Value {
+    let ops: serde_json::Map<String, Value> = report
+        .ops
+        .iter()
+        .map(|(op, stat)| (op.to_string(), stat.to_wire()))
+        .collect();
+    json!({
+        "type": "tick",
+        "tick": tick,
+        "warmup": report.warmup,
+        "elapsed": report.elapsed.as_secs_f64(),
+        "ops": ops,
+    })
+}
+
+pub fn tick_from_json(msg: &Value) -> Result<(u64, TickReport), ChumError> {
+    let bad = || ChumError::new("malformed tick");
+
+    let mut ops = HashMap::new();
+    for (name, stat) in msg["ops"].as_object().ok_or_else(bad)? {
+        let op = WIRE_OPS
+            .iter()
+            .find(|op| op.to_string() == *name)
+            .ok_or_else(bad)?;
+        ops.insert(*op, WorkerStat::from_wire(stat)?);
+    }
+
+    let elapsed = msg["elapsed"]
+        .as_f64()
+        .filter(|secs| *secs >= 0.0)
+        .ok_or_else(bad)?;
+    let report = TickReport {
+        warmup: msg["warmup"].as_bool().ok_or_else(bad)?,
+        elapsed: Duration::from_secs_f64(elapsed),
+        ops,
+    };
+    Ok((msg["tick"].as_u64().ok_or_else(bad)?, report))
+}
+
+/* Serve instructors, one at a time, until killed. */
+pub fn serve(listen: &str, allow_paths: bool) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(listen)?;
+    eprintln!("waiting for an instructor on {}", listener.local_addr()?);
+    attend(listener, allow_paths)
+}
+
+pub fn attend(
+    listener: TcpListener,
+    allow_paths: bool,
+) -> Result<(), Box<dyn Error>> {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let peer = match stream.peer_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => "unknown address".to_string(),
+        };
+
+        eprintln!("instructor connected from {}", peer);
+        match lesson(stream, allow_paths) {
+            Ok(()) => eprintln!("run for {} finished", peer),
+            Err(e) => eprintln!("run for {} failed: {}", peer, e),
+        }
+    }
+
+    Ok(())
+}
+
+/*
+ * The first setting that names a file or directory on the pupil's host: the
+ * target of an fs run, a read or write list, or a distribution loaded from a
+ * file.
+ */
+fn path_setting(protocol: &str, config: &Config) -> Option<&'static str> {
+    let names = ["target", "read-list", "write-list", "distribution"];
+    names
+        .iter()
+        .cloned()
+        .find(|name| match (*name, config.get(*name)) {
+            (_, None) => false,
+            ("target", _) => protocol == "fs",
+            ("distribution", Some(v)) => v.to_string().contains('@'),
+            _ => true,
+        })
+}
+
+/* Parse the settings for a run the same way as the command line. */
+fn prepare(msg: &Value, allow_paths: bool) -> Result<RunPlan, Box<dyn Error>> {
+    let bad = || ChumError::new("expected a run message");
+    if msg_type(msg) != "run" {
+        return Err(Box::new(bad()));
+    }
+    let protocol = msg["protocol"].as_str().ok_or_else(bad)?;
+    let config = msg["config"].as_object().ok_or_else(bad)?;
+
+    for name in LOCAL_ONLY.iter() {
+        if config.contains_key(*name) {
+            return Err(Box::new(ChumError::new(&format!(
+                "--{} can't be used with an instructor",
+                name
+            ))));
+        }
+    }
+    if !allow_paths {
+        if let Some(name) = path_setting(protocol, config) {
+            return Err(Box::new(ChumError::new(&format!(
+                "--{} names a path on the pupil, which it only accepts when \
+                 started with --allow-paths",
+                name
+            ))));
+        }
+    }
+
+    let mut argv = vec!["chum", "worker", protocol]
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    argv.extend(config::config_args(config, |_| false)?);
+    let matches = crate::cli()
+        .get_matches_from_safe(argv)
+        .map_err(|e| ChumError::new(&e.message))?;
+
+    let (_, protocol_name, protocol_args) = crate::protocol_matches(&matches);
+    crate::plan_run(protocol_name, protocol_args)
+}
+
+/* Run one workload for the instructor on the other end of 'stream'. */
+fn lesson(stream: TcpStream, allow_paths: bool) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let msg = recv(&mut reader)?
+        .ok_or_else(|| ChumError::new("instructor hung up"))?;
+    let mut plan = match prepare(&msg, allow_paths) {
+        Ok(plan) => plan,
+        Err(e) => {
+            send(
+                &mut writer,
+                &json!({ "type": "error", "msg": e.to_string() }),
+            )?;
+            return Err(e);
+        }
+    };
+    send(&mut writer, &json!({ "type": "ready" }))?;
+
+    match recv(&mut reader)? {
+        Some(ref msg) if msg_type(msg) == "start" => (),
+        _ => {
+            return Err(Box::new(ChumError::new(
+                "instructor didn't start the run",
+            )))
+        }
+    }
+
+    /* The run stops early if the instructor says so, or goes away. */
+    let stop = Arc::new(AtomicBool::new(false));
+    let instructor_stop = stop.clone();
+    thread::spawn(move || loop {
+        match recv(&mut reader) {
+            Ok(Some(ref msg)) if msg_type(msg) != "stop" => continue,
+            _ => {
+                instructor_stop.store(true, Ordering::SeqCst);
+                return;
+            }
+        }
+    });
+
+    /*
+     * Stats come back from the run a tick at a time. The channel disconnects
+     * once the run is over and the plan, with its sender, has been dropped.
+     */
+    let (tx, rx) = channel();
+    plan.sopts.report = Some(tx);
+    let run_stop = stop.clone();
+    let runner = thread::spawn(move || {
+        crate::run(
+            &plan.workeropts,
+            plan.conc,
+            &plan.sopts,
+            &plan.caps,
+            &run_stop,
+        )
+    });
+
+    for (tick, report) in rx.iter().enumerate() {
+        if send(&mut writer, &tick_to_json(tick as u64, &report)).is_err() {
+            stop.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let stats = runner
+        .join()
+        .map_err(|_| ChumError::new("run thread panicked"))?;
+    send(
+        &mut writer,
+        &json!({ "type": "done", "interrupted": stats.interrupted }),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::WorkerInfo;
+
+    #[test]
+    fn test_tick_roundtrip() -> Result<(), ChumError> {
+        let mut stat = WorkerStat::new();
+        for ms in &[1, 2, 3, 50, 900] {
+            stat.add_result(&WorkerInfo {
+                id: thread::current().id(),
+                op: Operation::Read,
+                size: 4096,
+                ttfb: Duration::from_micros(ms * 100),
+                rtt: Duration::from_millis(*ms),
+                age: Some(Duration::from_secs(*ms)),
+            });
+        }
+        let mut errors = WorkerStat::new();
+        errors.objs = 2;
+
+        let mut ops = HashMap::new();
+        ops.insert(Operation::Read, stat);
+        ops.insert(Operation::Error, errors);
+        let report = TickReport {
+            warmup: true,
+            elapsed: Duration::from_millis(2500),
+            ops,
+        };
+
+        /* Send it through a buffer, the way it would go over a socket. */
+        let mut buf = Vec::new();
+        send(&mut buf, &tick_to_json(7, &report))?;
+        let msg = recv(&mut &buf[..])?.expect("message should be there");
+        assert_eq!(msg_type(&msg), "tick");
+
+        let (tick, got) = tick_from_json(&msg)?;
+        assert_eq!(tick, 7);
+        assert!(got.warmup);
+        assert_eq!(got.elapsed, Duration::from_millis(2500));
+        assert_eq!(got.ops.len(), 2);
+        assert_eq!(got.ops[&Operation::Error].objs, 2);
+
+        let (sent, got) =
+            (&report.ops[&Operation::Read], &got.ops[&Operation::Read]);
+        assert_eq!((got.objs, got.data), (5, 5 * 4096));
+        assert_eq!((got.ttfb, got.rtt), (sent.ttfb, sent.rtt));
+        assert_eq!(
+            WorkerStat::percentiles(&got.rtt_hist),
+            WorkerStat::percentiles(&sent.rtt_hist)
+        );
+        assert_eq!(got.ttfb_hist.len(), sent.ttfb_hist.len());
+        assert_eq!(got.age_hist.max(), sent.age_hist.max());
+
+        assert!(recv(&mut &buf[buf.len()..])?.is_none());
+        assert!(tick_from_json(&json!({ "type": "tick" })).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_settings() {
+        let run = |protocol: &str, config: Value| {
+            let mut msg = json!({ "type": "run", "protocol": protocol });
+            msg["config"] = config;
+            msg
+        };
+        let refused = |msg: &Value| match prepare(msg, false) {
+            Err(e) => e.to_string().contains("--allow-paths"),
+            Ok(_) => false,
+        };
+
+        assert!(refused(&run("fs", json!({ "target": "/tmp" }))));
+        assert!(refused(&run(
+            "s3",
+            json!({ "target": "localhost:9000", "read-list": "list" })
+        )));
+        assert!(refused(&run(
+            "s3",
+            json!({ "target": "localhost:9000", "distribution": "@sizes" })
+        )));
+        assert!(!refused(&run(
+            "s3",
+            json!({ "target": "localhost:9000", "distribution": "128k" })
+        )));
+        assert!(prepare(&run("fs", json!({ "target": "/tmp" })), true).is_ok());
+    }
+}
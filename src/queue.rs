@@ -8,20 +8,32 @@
 
 use rand::Rng;
 
+use std::collections::VecDeque;
 use std::error;
 use std::fmt;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 
-const DEF_QUEUE_CAP: usize = 1_000_000;
+pub const DEF_QUEUE_CAP: usize = 1_000_000;
+
+/* Defaults for the tunable queue modes. */
+const DEF_ZIPF_SKEW: f64 = 1.0;
+const DEF_HOTSET_READS: u32 = 80;
+const DEF_HOTSET_OBJS: u32 = 20;
 
 /*
  * Operating modes that the queue supports. See the block comment above the
  * Queue impl for an explanation.
  */
+#[derive(Clone, Copy)]
 pub enum QueueMode {
     Lru,
     Mru,
     Rand,
+    Zipf(f64),        /* skew */
+    HotSet(u32, u32), /* percent of accesses, percent of objects */
 }
 
 #[derive(Debug)]
@@ -44,10 +56,31 @@ impl fmt::Display for QueueModeError {
 impl FromStr for QueueMode {
     type Err = QueueModeError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mode = match s {
-            "lru" => Some(QueueMode::Lru),
-            "mru" => Some(QueueMode::Mru),
-            "rand" => Some(QueueMode::Rand),
+        let tok: Vec<&str> = s.split(':').collect();
+        let mode = match (tok[0], tok.len()) {
+            ("lru", 1) => Some(QueueMode::Lru),
+            ("mru", 1) => Some(QueueMode::Mru),
+            ("rand", 1) => Some(QueueMode::Rand),
+            ("zipf", 1) => Some(QueueMode::Zipf(DEF_ZIPF_SKEW)),
+            ("zipf", 2) => match tok[1].parse::<f64>() {
+                Ok(skew) if skew > 0.0 && skew.is_finite() => {
+                    Some(QueueMode::Zipf(skew))
+                }
+                _ => None,
+            },
+            ("hotset", 1) => {
+                Some(QueueMode::HotSet(DEF_HOTSET_READS, DEF_HOTSET_OBJS))
+            }
+            ("hotset", 3) => {
+                match (tok[1].parse::<u32>(), tok[2].parse::<u32>()) {
+                    (Ok(reads), Ok(objs))
+                        if reads <= 100 && objs > 0 && objs <= 100 =>
+                    {
+                        Some(QueueMode::HotSet(reads, objs))
+                    }
+                    _ => None,
+                }
+            }
             _ => None,
         };
 
@@ -60,167 +93,970 @@ impl FromStr for QueueMode {
 
 impl fmt::Display for QueueMode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let strmode = match self {
-            QueueMode::Lru => "lru",
-            QueueMode::Mru => "mru",
-            QueueMode::Rand => "rand",
+        match self {
+            QueueMode::Lru => write!(f, "lru"),
+            QueueMode::Mru => write!(f, "mru"),
+            QueueMode::Rand => write!(f, "rand"),
+            QueueMode::Zipf(skew) => write!(f, "zipf:{}", skew),
+            QueueMode::HotSet(reads, objs) => {
+                write!(f, "hotset:{}:{}", reads, objs)
+            }
+        }
+    }
+}
+
+/*
+ * Draw a rank in [0, n) from a Zipf distribution with the given skew. Rank 0
+ * is the most likely.
+ *
+ * This is the rejection-inversion method from Hörmann and Derflinger, which
+ * needs no per-n setup. That matters because the number of items in the
+ * queue changes all the time.
+ */
+fn zipf_rank<R: Rng>(rng: &mut R, n: usize, skew: f64) -> usize {
+    let nf = n as f64;
+    let (q, t) = if skew != 1.0 {
+        let q = 1.0 / (1.0 - skew);
+        (q, (nf.powf(1.0 - skew) - skew) * q)
+    } else {
+        (1.0, 1.0 + nf.ln())
+    };
+
+    loop {
+        let pt = rng.gen::<f64>() * t;
+        let inv_b = if pt <= 1.0 {
+            pt
+        } else if skew != 1.0 {
+            (pt * (1.0 - skew) + skew).powf(q)
+        } else {
+            (pt - 1.0).exp()
         };
-        write!(f, "{}", strmode)
+
+        let x = (inv_b + 1.0).floor();
+        let mut ratio = x.powf(-skew);
+        if x > 1.0 {
+            ratio *= inv_b.powf(skew);
+        }
+        if rng.gen::<f64>() < ratio {
+            return (x as usize).min(n) - 1;
+        }
+    }
+}
+
+/*
+ * Draw a rank in [0, n) such that 'reads' percent of draws land in the first
+ * 'objs' percent of ranks.
+ */
+fn hotset_rank<R: Rng>(rng: &mut R, n: usize, reads: u32, objs: u32) -> usize {
+    let hot = (n * objs as usize / 100).max(1);
+    if hot >= n || rng.gen_range(0, 100) < reads {
+        rng.gen_range(0, hot)
+    } else {
+        rng.gen_range(hot, n)
+    }
+}
+
+/*
+ * Items that know how long ago they were written, so that the queue can hold
+ * them back from reads and deletes until they're old enough. Items of unknown
+ * age are never held back.
+ */
+pub trait Aged {
+    fn age(&self) -> Option<Duration> {
+        None
     }
 }
 
 pub struct Queue<T> {
-    items: Vec<T>,
+    items: VecDeque<T>,
     cap: usize,
     mode: QueueMode,
-    cursor: usize,
+    min_age: Option<Duration>,
+    young: VecDeque<T>, /* oldest first, waiting to reach 'min_age' */
+    dropped: u64,       /* items replaced or turned away for lack of room */
 }
 
 /*
  * This is a simple queue data structure. It supports a few different modes of
  * operation.
  *
+ * Items are kept in a ring buffer ordered by age: the front is the oldest item
+ * and the back is the newest. Every operation is O(1) in every mode.
+ *
  * Modes:
- * - Lru (least recently used). Operates like a FIFO queue. When the queue fills
- *   up new items replace the oldest items.
- * - Mru (most recently used). Operates like a LIFO queue (AKA a stack). When
+ * - Lru (least recently used). The accessor returns the least recently used
+ *   item and marks it as the most recently used, so reads cycle through every
+ *   item oldest-first. When the queue fills up new items replace the least
+ *   recently used item, and removal takes the least recently used item.
+ * - Mru (most recently used). Operates like a LIFO queue (AKA a stack). The
+ *   accessor returns the newest item and removal takes the newest item. When
  *   the queue is at capacity the 'bottom' item in the stack is removed and the
  *   new item is added to the top of the stack.
  * - Rand (random). Operates like an array. Random items are returned when using
- *   the accessor function. New items replace a random item.
+ *   the accessor function and random items are removed. New items replace a
+ *   random item.
+ * - Zipf. Items are ranked by age, newest first, and the accessor picks a rank
+ *   from a Zipf distribution. Higher skew concentrates accesses on the newest
+ *   items. When the queue fills up new items replace the oldest items, and
+ *   removal takes the oldest item.
+ * - HotSet. Like Zipf, but a fixed percentage of accesses go to a fixed
+ *   percentage of the newest items (e.g. 80% of reads hit 20% of objects) and
+ *   the rest are spread across the remaining items.
+ *
+ * With a minimum age set, items that are too young wait in a separate FIFO
+ * and join the queue once they're old enough, so reads and deletes only see
+ * items written at least that long ago. Items are assumed to arrive oldest
+ * first. Waiting items count toward 'cap'. Once the queue is full, a new item
+ * that has to wait pushes out one that doesn't, the way the mode replaces
+ * items, and is dropped if every item is still waiting.
  */
-impl<T> Queue<T> {
-    pub fn new(mode: QueueMode) -> Queue<T> {
+impl<T: Aged> Queue<T> {
+    /*
+     * Queues bigger than the default grow as they fill, rather than taking
+     * all of their memory up front.
+     */
+    pub fn with_capacity(mode: QueueMode, cap: usize) -> Queue<T> {
         Queue {
-            items: Vec::with_capacity(DEF_QUEUE_CAP),
-            cap: DEF_QUEUE_CAP,
+            items: VecDeque::with_capacity(cap.min(DEF_QUEUE_CAP)),
+            cap,
             mode,
-            cursor: 0,
+            min_age: None,
+            young: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn set_min_age(&mut self, min_age: Duration) {
+        self.min_age = Some(min_age);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty() && self.young.is_empty()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /* Items in the queue, waiting or not. */
+    fn len(&self) -> usize {
+        self.items.len() + self.young.len()
+    }
+
+    /* Items ready to be read, once any that are now old enough join them. */
+    fn ready<R: Rng>(&mut self, rng: &mut R) -> usize {
+        self.promote(rng);
+        self.items.len()
+    }
+
+    /*
+     * Return the item at 'rank' by age, newest first, or the oldest item if
+     * there are fewer than that.
+     */
+    fn get_ranked<R: Rng>(&mut self, rng: &mut R, rank: usize) -> Option<&T> {
+        let len = self.ready(rng);
+        if len == 0 {
+            return None;
+        }
+        self.items.get(len - 1 - rank.min(len - 1))
+    }
+
+    fn old_enough(&self, qi: &T) -> bool {
+        match (self.min_age, qi.age()) {
+            (Some(min_age), Some(age)) => age >= min_age,
+            _ => true,
+        }
+    }
+
+    /* Move any waiting items that are now old enough into the queue. */
+    fn promote<R: Rng>(&mut self, rng: &mut R) {
+        while let Some(qi) = self.young.front() {
+            if !self.old_enough(qi) {
+                break;
+            }
+            let qi = self.young.pop_front().unwrap();
+            self.push(qi, rng);
         }
     }
 
     /*
      * Inserts an item into the queue.
-     * Removes an item if the queue has hit its capacity.
+     * Replaces an item if the queue has hit its capacity.
+     *
+     * Random choices come from the caller's 'rng', so that a seeded worker
+     * makes the same choices from one run to the next.
+     */
+    pub fn insert<R: Rng>(&mut self, qi: T, rng: &mut R) {
+        if self.cap == 0 {
+            self.dropped += 1;
+            return;
+        }
+
+        if !self.young.is_empty() || !self.old_enough(&qi) {
+            if self.len() < self.cap || self.evict(rng) {
+                self.young.push_back(qi);
+            } else {
+                self.dropped += 1;
+            }
+            return;
+        }
+        self.push(qi, rng);
+    }
+
+    /*
+     * Make room by dropping an item that's old enough, the way the mode
+     * replaces items. Returns false if there's no such item.
      */
-    pub fn insert(&mut self, qi: T) {
-        if self.items.len() < self.cap {
-            self.items.push(qi);
+    fn evict<R: Rng>(&mut self, rng: &mut R) -> bool {
+        if self.items.is_empty() {
+            return false;
+        }
+
+        self.dropped += 1;
+        match self.mode {
+            QueueMode::Rand => {
+                let idx = rng.gen_range(0, self.items.len());
+                self.items.swap_remove_back(idx);
+            }
+            _ => {
+                self.items.pop_front();
+            }
+        }
+        true
+    }
+
+    /* Add an item that's old enough, replacing one if the queue is full. */
+    fn push<R: Rng>(&mut self, qi: T, rng: &mut R) {
+        if self.len() < self.cap {
+            self.items.push_back(qi);
             return;
         }
 
-        self.replace(qi);
+        self.dropped += 1;
+        match self.mode {
+            QueueMode::Rand => {
+                let idx = rng.gen_range(0, self.items.len());
+                self.items[idx] = qi;
+            }
+            _ => {
+                self.items.pop_front();
+                self.items.push_back(qi);
+            }
+        }
     }
 
     /*
      * Return an item from the queue.
      * Returns None if nothing is in the queue.
      */
-    pub fn get(&mut self) -> Option<&T> {
+    pub fn get<R: Rng>(&mut self, rng: &mut R) -> Option<&T> {
+        self.promote(rng);
         if self.items.is_empty() {
             return None;
         }
 
+        let len = self.items.len();
         match self.mode {
-            QueueMode::Lru => self.items.get(0),
-            QueueMode::Mru => self.items.get(self.items.len()),
-            QueueMode::Rand => self
-                .items
-                .get(rand::thread_rng().gen_range(0, self.items.len())),
+            QueueMode::Lru => {
+                /* This item is now the most recently used. */
+                let qi = self.items.pop_front().unwrap();
+                self.items.push_back(qi);
+                self.items.back()
+            }
+            QueueMode::Mru => self.items.back(),
+            QueueMode::Rand => self.items.get(rng.gen_range(0, len)),
+            QueueMode::Zipf(skew) => {
+                let rank = zipf_rank(rng, len, skew);
+                self.items.get(len - 1 - rank)
+            }
+            QueueMode::HotSet(reads, objs) => {
+                let rank = hotset_rank(rng, len, reads, objs);
+                self.items.get(len - 1 - rank)
+            }
         }
     }
 
-    pub fn remove(&mut self) -> Option<T> {
+    /*
+     * Remove an item from the queue.
+     * Returns None if nothing is in the queue.
+     */
+    pub fn remove<R: Rng>(&mut self, rng: &mut R) -> Option<T> {
+        self.promote(rng);
         if self.items.is_empty() {
             return None;
         }
 
         match self.mode {
-            QueueMode::Lru => Some(self.items.remove(0)),
-            QueueMode::Mru => Some(self.items.remove(0)),
+            QueueMode::Mru => self.items.pop_back(),
             QueueMode::Rand => {
-                let ret = Some(self.items.swap_remove(self.cursor));
-                if !self.items.is_empty() {
-                    self.cursor = (self.cursor + 1) % self.items.len();
-                } else {
-                    self.cursor = 0;
-                }
-                ret
+                let idx = rng.gen_range(0, self.items.len());
+                self.items.swap_remove_back(idx)
             }
+            _ => self.items.pop_front(),
         }
     }
+}
 
-    pub fn replace(&mut self, qi: T) {
-        if self.items.is_empty() {
-            return;
+/*
+ * A queue split into shards, each a Queue behind its own lock, so that workers
+ * rarely wait on each other to get at it.
+ *
+ * New items are dealt out to the shards in turn. Every shard then holds a
+ * similar number of items of similar ages, and together they behave much like
+ * one big queue in the same mode:
+ * - Lru. Reads and removals also take the shards in turn, so they visit items
+ *   in about the order they were written.
+ * - Mru. Reads and removals go to the shard that got the newest item, and
+ *   removals step back a shard each time, like popping a stack.
+ * - Rand. A random item from a random shard.
+ * - Zipf and HotSet. Reads rank the items of every shard together, newest
+ *   first. Since items are dealt out in turn, with N shards the item of rank
+ *   r is in the r % N'th shard back from the one that got the newest item,
+ *   and has rank r / N within it. Removals take the shards in turn, oldest
+ *   item first.
+ *
+ * Each shard holds its share of the capacity and drops items on its own once
+ * that's full. The shards fill up together, since items are dealt out evenly,
+ * but uneven removals can leave one shard dropping items while another still
+ * has room.
+ *
+ * The order is only approximate while workers race each other, or after a
+ * failed delete puts an item back out of turn. If the chosen shard is empty
+ * the others are tried in turn. With a single shard this is just a Queue
+ * behind a lock, and every choice is exactly the same.
+ */
+pub struct ShardedQueue<T> {
+    shards: Vec<Mutex<Queue<T>>>,
+    mode: QueueMode,
+    next: AtomicUsize, /* items dealt out so far, less Mru removals */
+    turn: AtomicUsize, /* Lru reads and oldest-first removals so far */
+    dropped: AtomicU64, /* items dropped by every shard together */
+}
+
+impl<T: Aged + Clone> ShardedQueue<T> {
+    pub fn new(mode: QueueMode, shards: usize) -> ShardedQueue<T> {
+        ShardedQueue::with_capacity(mode, DEF_QUEUE_CAP, shards)
+    }
+
+    /*
+     * The capacity is split as evenly as it goes between the shards, and
+     * there are no more shards than items.
+     */
+    pub fn with_capacity(
+        mode: QueueMode,
+        cap: usize,
+        shards: usize,
+    ) -> ShardedQueue<T> {
+        let shards = shards.min(cap).max(1);
+        let share = |i: usize| cap / shards + usize::from(i < cap % shards);
+        ShardedQueue {
+            shards: (0..shards)
+                .map(|i| Mutex::new(Queue::with_capacity(mode, share(i))))
+                .collect(),
+            mode,
+            next: AtomicUsize::new(0),
+            turn: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
         }
+    }
 
-        let len = self.items.len();
+    pub fn set_min_age(&mut self, min_age: Duration) {
+        for shard in self.shards.iter_mut() {
+            shard.get_mut().unwrap().set_min_age(min_age);
+        }
+    }
 
-        match self.mode {
-            QueueMode::Lru => self.items[len] = qi,
-            QueueMode::Mru => self.items[len] = qi,
-            QueueMode::Rand => {
-                self.items[self.cursor] = qi;
-                self.cursor = (self.cursor + 1) % len;
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.lock().unwrap().is_empty())
+    }
+
+    /* A random shard, without touching 'rng' when there's only one. */
+    fn random_shard<R: Rng>(&self, rng: &mut R) -> usize {
+        match self.shards.len() {
+            1 => 0,
+            n => rng.gen_range(0, n),
+        }
+    }
+
+    /*
+     * Run 'f' on the shard at 'start', or on the next one along that isn't
+     * empty.
+     */
+    fn try_shards<U, F>(&self, start: usize, mut f: F) -> Option<U>
+    where
+        F: FnMut(&mut Queue<T>) -> Option<U>,
+    {
+        let n = self.shards.len();
+        (0..n)
+            .find_map(|i| f(&mut self.shards[(start + i) % n].lock().unwrap()))
+    }
+
+    /*
+     * Inserts an item into the next shard. Returns true if this is the first
+     * item dropped from the queue for lack of room.
+     */
+    pub fn insert<R: Rng>(&self, qi: T, rng: &mut R) -> bool {
+        let idx = self.next.fetch_add(1, Ordering::SeqCst) % self.shards.len();
+        let mut shard = self.shards[idx].lock().unwrap();
+        let dropped = shard.dropped();
+        shard.insert(qi, rng);
+
+        let more = shard.dropped() - dropped;
+        more > 0 && self.dropped.fetch_add(more, Ordering::SeqCst) == 0
+    }
+
+    /*
+     * Return a copy of an item from the queue.
+     * Returns None if nothing is in the queue.
+     */
+    pub fn get<R: Rng>(&self, rng: &mut R) -> Option<T> {
+        let n = self.shards.len();
+        let start = match self.mode {
+            QueueMode::Lru => self.turn.fetch_add(1, Ordering::SeqCst) % n,
+            QueueMode::Mru => {
+                self.next.load(Ordering::SeqCst).wrapping_sub(1) % n
             }
+            QueueMode::Rand => self.random_shard(rng),
+            _ => return self.get_ranked(rng),
+        };
+        self.try_shards(start, |q| q.get(rng).cloned())
+    }
+
+    /*
+     * Return a copy of an item picked by its rank in the whole queue. Rather
+     * than lock every shard to count the items, the queue is taken to hold N
+     * times as many as the shard that got the newest item. The shards hold
+     * about as many items each, and a rank past the end of a shard gets its
+     * oldest item.
+     */
+    fn get_ranked<R: Rng>(&self, rng: &mut R) -> Option<T> {
+        let n = self.shards.len();
+        let newest = self.next.load(Ordering::SeqCst).wrapping_sub(1) % n;
+        let len = n * self.shards[newest].lock().unwrap().ready(rng);
+        if len == 0 {
+            /* Only uneven removals leave the newest shard empty. */
+            return self.try_shards(newest, |q| q.get(rng).cloned());
         }
+
+        let rank = match self.mode {
+            QueueMode::Zipf(skew) => zipf_rank(rng, len, skew),
+            QueueMode::HotSet(reads, objs) => {
+                hotset_rank(rng, len, reads, objs)
+            }
+            _ => return None,
+        };
+        let start = (newest + n - rank % n) % n;
+        self.try_shards(start, |q| q.get_ranked(rng, rank / n).cloned())
+    }
+
+    /*
+     * Remove an item from the queue.
+     * Returns None if nothing is in the queue.
+     */
+    pub fn remove<R: Rng>(&self, rng: &mut R) -> Option<T> {
+        let n = self.shards.len();
+        let start = match self.mode {
+            QueueMode::Mru => {
+                let newest = |next: usize| next.checked_sub(1);
+                match self.next.fetch_update(
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                    newest,
+                ) {
+                    Ok(next) => (next - 1) % n,
+                    Err(_) => 0,
+                }
+            }
+            QueueMode::Rand => self.random_shard(rng),
+            _ => self.turn.fetch_add(1, Ordering::SeqCst) % n,
+        };
+        self.try_shards(start, |q| q.remove(rng))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::{thread_rng, SeedableRng};
+    use std::sync::Arc;
+    use std::thread;
     use std::time::Instant;
 
+    /* Most tests queue plain numbers, which are never held back. */
+    impl Aged for usize {}
+
+    /* Items written at the given instant. */
+    impl Aged for Instant {
+        fn age(&self) -> Option<Duration> {
+            Some(self.elapsed())
+        }
+    }
+
     /*
      * Historically we've had problems with the naiive queue implementation.
      * Specifically the naiive implementation isn't efficient when new items
      * are added or removed from a full queue.
      *
-     * This test is like a benchmark, except it doesn't fit the rust
-     * benchmarking model because the routine here can be fairly expensive
-     * depending on the length of the queue.
+     * These checks fill a queue, overwrite every item in it, read from it,
+     * and then empty it, for every mode. Along the way they check that the
+     * mode behaves as documented, and print how long each step took.
      *
-     * If you're interested in seeing the results that are printed here
-     * (timings for filling a queue and then overwriting all items within), I
-     * recommend running `cargo test -- --nocapture` to see the timings.
+     * The tests run them on a small queue. The benchmarks run them on a
+     * queue of the default size, which takes a while, so they're ignored
+     * unless asked for. If you're interested in seeing the timings, I
+     * recommend running `cargo test -- --ignored --nocapture`.
      */
 
-    #[test]
-    fn test_queue_overwrite() {
-        let mut q = Queue::new(QueueMode::Rand);
+    const TEST_QUEUE_CAP: usize = 10_000;
+
+    fn all_modes() -> Vec<QueueMode> {
+        vec![
+            QueueMode::Lru,
+            QueueMode::Mru,
+            QueueMode::Rand,
+            QueueMode::Zipf(DEF_ZIPF_SKEW),
+            QueueMode::HotSet(DEF_HOTSET_READS, DEF_HOTSET_OBJS),
+        ]
+    }
+
+    /* Run 'f' on 'n' items and print how long it took. */
+    fn timed<F: FnOnce()>(mode: &str, what: &str, n: usize, f: F) {
         let start = Instant::now();
-        for _ in 0..DEF_QUEUE_CAP {
-            q.insert("testobj".to_string());
+        f();
+        println!(
+            "{}: {} {} items took {}ms",
+            mode,
+            what,
+            n,
+            start.elapsed().as_millis()
+        );
+    }
+
+    fn check_overwrite(n: usize) {
+        for mode in all_modes() {
+            let name = mode.to_string();
+            let ordered = !matches!(mode, QueueMode::Rand);
+            let mut rng = thread_rng();
+            let mut q = Queue::with_capacity(mode, n);
+
+            timed(&name, "adding", n, || {
+                for i in 0..n {
+                    q.insert(i, &mut rng);
+                }
+            });
+            assert_eq!(q.items.len(), n, "{}", name);
+
+            timed(&name, "adding overflow", n, || {
+                for i in n..(2 * n) {
+                    q.insert(i, &mut rng);
+                }
+            });
+            assert_eq!(q.items.len(), n, "{}", name);
+            assert_eq!(q.dropped(), n as u64, "{}", name);
+
+            /*
+             * Every mode but rand replaces the oldest items, so only the
+             * overflow items are left.
+             */
+            if ordered {
+                assert!(q.items.iter().all(|i| *i >= n), "{}", name);
+            }
+
+            timed(&name, "getting", n, || {
+                for _ in 0..n {
+                    assert!(q.get(&mut rng).is_some());
+                }
+            });
         }
-        let end = start.elapsed().as_millis();
-        println!("adding {} items took {}ms", DEF_QUEUE_CAP, end);
+    }
 
-        let noverflow = DEF_QUEUE_CAP;
-        let start = Instant::now();
-        for _ in 0..noverflow {
-            q.insert("testobj".to_string());
+    fn check_clear(n: usize) {
+        for mode in all_modes() {
+            let name = mode.to_string();
+            let mut rng = thread_rng();
+            let mut q = Queue::with_capacity(mode, n);
+            for i in 0..n {
+                q.insert(i, &mut rng);
+            }
+
+            let mut removed = Vec::with_capacity(n);
+            timed(&name, "removing", n, || {
+                while let Some(i) = q.remove(&mut rng) {
+                    removed.push(i);
+                }
+            });
+            assert!(q.items.is_empty(), "{}", name);
+            assert_eq!(q.get(&mut rng), None, "{}", name);
+            assert_eq!(removed.len(), n, "{}", name);
+
+            removed.sort();
+            removed.dedup();
+            assert_eq!(removed.len(), n, "{}", name);
         }
-        let end = start.elapsed().as_millis();
-        println!("adding {} overflow items took {}ms", noverflow, end);
+    }
+
+    #[test]
+    fn test_queue_overwrite() {
+        check_overwrite(TEST_QUEUE_CAP);
     }
 
     #[test]
     fn test_queue_clear() {
-        let mut q = Queue::new(QueueMode::Rand);
-        let start = Instant::now();
-        for _ in 0..DEF_QUEUE_CAP {
-            q.insert("testobj".to_string());
+        check_clear(TEST_QUEUE_CAP);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_queue_overwrite() {
+        check_overwrite(DEF_QUEUE_CAP);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_queue_clear() {
+        check_clear(DEF_QUEUE_CAP);
+    }
+
+    #[test]
+    fn test_queue_lru() {
+        let mut rng = thread_rng();
+        let mut q = Queue::with_capacity(QueueMode::Lru, 3);
+        for i in 0..3 {
+            q.insert(i, &mut rng);
         }
-        let end = start.elapsed().as_millis();
-        println!("adding {} items took {}ms", DEF_QUEUE_CAP, end);
 
-        let start = Instant::now();
-        for _ in 0..DEF_QUEUE_CAP {
-            q.remove();
+        /* Reads cycle through the items, least recently used first. */
+        assert_eq!(q.get(&mut rng), Some(&0));
+        assert_eq!(q.get(&mut rng), Some(&1));
+        assert_eq!(q.get(&mut rng), Some(&2));
+        assert_eq!(q.get(&mut rng), Some(&0));
+
+        /* 1 is now the least recently used item, so it is replaced. */
+        q.insert(3, &mut rng);
+        assert_eq!(q.get(&mut rng), Some(&2));
+        assert_eq!(q.remove(&mut rng), Some(0));
+        assert_eq!(q.remove(&mut rng), Some(3));
+        assert_eq!(q.remove(&mut rng), Some(2));
+        assert_eq!(q.remove(&mut rng), None);
+    }
+
+    #[test]
+    fn test_queue_mru() {
+        let mut rng = thread_rng();
+        let mut q = Queue::with_capacity(QueueMode::Mru, 3);
+        for i in 0..3 {
+            q.insert(i, &mut rng);
+        }
+
+        /* Reads and removals take the top of the stack. */
+        assert_eq!(q.get(&mut rng), Some(&2));
+        assert_eq!(q.get(&mut rng), Some(&2));
+
+        /* A full stack loses its bottom item. */
+        q.insert(3, &mut rng);
+        assert_eq!(q.get(&mut rng), Some(&3));
+        assert_eq!(q.remove(&mut rng), Some(3));
+        assert_eq!(q.remove(&mut rng), Some(2));
+        assert_eq!(q.remove(&mut rng), Some(1));
+        assert_eq!(q.remove(&mut rng), None);
+    }
+
+    #[test]
+    fn test_queue_rand() {
+        let mut rng = thread_rng();
+        let mut q = Queue::with_capacity(QueueMode::Rand, 100);
+        for i in 0..100 {
+            q.insert(i, &mut rng);
+        }
+
+        /* Random reads eventually touch most items. */
+        let mut seen = [false; 100];
+        for _ in 0..10_000 {
+            seen[*q.get(&mut rng).unwrap()] = true;
+        }
+        assert!(seen.iter().all(|s| *s));
+
+        /* A new item replaces exactly one existing item. */
+        q.insert(100, &mut rng);
+        assert_eq!(q.items.len(), 100);
+        assert!(q.items.contains(&100));
+    }
+
+    #[test]
+    fn test_queue_mode_parse() {
+        for mode in &["lru", "mru", "rand", "zipf:1.2", "hotset:90:10"] {
+            assert_eq!(mode.parse::<QueueMode>().unwrap().to_string(), *mode);
+        }
+        assert_eq!("zipf".parse::<QueueMode>().unwrap().to_string(), "zipf:1");
+        assert_eq!(
+            "hotset".parse::<QueueMode>().unwrap().to_string(),
+            "hotset:80:20"
+        );
+
+        for mode in &["fifo", "zipf:0", "zipf:x", "hotset:90", "hotset:50:0"] {
+            assert!(mode.parse::<QueueMode>().is_err());
+        }
+    }
+
+    /*
+     * Count how often each age rank (0 is newest) is returned by a queue split
+     * into the given number of shards.
+     */
+    fn rank_counts(
+        mode: QueueMode,
+        shards: usize,
+        nitems: usize,
+        ngets: usize,
+    ) -> Vec<usize> {
+        let mut rng = thread_rng();
+        let q = ShardedQueue::with_capacity(mode, nitems, shards);
+        for i in 0..nitems {
+            q.insert(i, &mut rng);
+        }
+
+        let mut counts = vec![0; nitems];
+        for _ in 0..ngets {
+            let item = q.get(&mut rng).unwrap();
+            counts[nitems - 1 - item] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_queue_zipf() {
+        /* Sharding doesn't change which items are popular. */
+        for shards in &[1, 8] {
+            let counts =
+                rank_counts(QueueMode::Zipf(1.0), *shards, 1000, 100_000);
+
+            /*
+             * With a skew of 1 rank r is picked about 1/(r+1) as often as 0,
+             * even when the ranks are in different shards.
+             */
+            assert!(counts[0] > counts[1], "{} shards", shards);
+            assert!(counts[1] > counts[9], "{} shards", shards);
+            assert!(counts[0] > 4 * counts[7], "{} shards", shards);
+            assert!(counts[0] > 10 * counts[99], "{} shards", shards);
+            assert!(counts[..10].iter().sum::<usize>() > 30_000);
+        }
+    }
+
+    #[test]
+    fn test_queue_hotset() {
+        for shards in &[1, 8] {
+            let counts =
+                rank_counts(QueueMode::HotSet(90, 10), *shards, 1000, 100_000);
+            let hot: usize = counts[..100].iter().sum();
+            assert!(hot > 88_000 && hot < 92_000, "{} shards", shards);
+        }
+    }
+
+    #[test]
+    fn test_queue_ordered_remove() {
+        let mut rng = thread_rng();
+        let mut q = Queue::with_capacity(QueueMode::HotSet(100, 1), 10);
+        for i in 0..10 {
+            q.insert(i, &mut rng);
+        }
+
+        /* The oldest items are removed first, the newest are the hot set. */
+        assert_eq!(q.remove(&mut rng), Some(0));
+        assert_eq!(q.remove(&mut rng), Some(1));
+        q.insert(10, &mut rng);
+        assert_eq!(q.get(&mut rng), Some(&10));
+        assert_eq!(q.remove(&mut rng), Some(2));
+
+        assert!(!q.is_empty());
+        while q.remove(&mut rng).is_some() {}
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn test_queue_seeded() {
+        /* The same seed picks the same items, whatever the mode. */
+        for mode in all_modes() {
+            let name = mode.to_string();
+            let picks = |seed: u64| -> Vec<usize> {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let mut q = Queue::with_capacity(
+                    mode.to_string().parse().unwrap(),
+                    100,
+                );
+                for i in 0..150 {
+                    q.insert(i, &mut rng);
+                }
+                let mut picks: Vec<usize> =
+                    (0..100).map(|_| *q.get(&mut rng).unwrap()).collect();
+                picks.extend((0..10).map(|_| q.remove(&mut rng).unwrap()));
+                picks
+            };
+            assert_eq!(picks(1), picks(1), "{}", name);
+            if !matches!(mode, QueueMode::Lru | QueueMode::Mru) {
+                assert_ne!(picks(1), picks(2), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_queue_min_age() {
+        let mut rng = thread_rng();
+        let mut q = Queue::with_capacity(QueueMode::Lru, 10);
+        q.set_min_age(Duration::from_millis(100));
+
+        let old = Instant::now() - Duration::from_secs(1);
+        let new = Instant::now();
+        q.insert(old, &mut rng);
+        q.insert(new, &mut rng);
+
+        /* The new item waits until it's old enough. */
+        assert_eq!(q.remove(&mut rng), Some(old));
+        assert_eq!(q.get(&mut rng), None);
+        assert!(!q.is_empty());
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(q.get(&mut rng), Some(&new));
+        assert_eq!(q.remove(&mut rng), Some(new));
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn test_queue_min_age_cap() {
+        let mut rng = thread_rng();
+        let mut q = Queue::with_capacity(QueueMode::Lru, 3);
+        q.set_min_age(Duration::from_millis(100));
+
+        let now = Instant::now();
+        let old: Vec<Instant> =
+            (0..2).map(|i| now - Duration::from_secs(2 - i)).collect();
+        let new: Vec<Instant> = (0..4)
+            .map(|i| now - Duration::from_millis(10 - i))
+            .collect();
+        for qi in old.iter().chain(new.iter()) {
+            q.insert(*qi, &mut rng);
+        }
+
+        /*
+         * Waiting items count toward the cap. They push out the old items,
+         * and the last one is dropped once every item is waiting.
+         */
+        assert_eq!(q.len(), 3);
+        assert_eq!(q.dropped(), 3);
+        assert_eq!(q.remove(&mut rng), None);
+
+        thread::sleep(Duration::from_millis(150));
+        let left: Vec<Instant> =
+            std::iter::from_fn(|| q.remove(&mut rng)).collect();
+        assert_eq!(left, new[..3].to_vec());
+    }
+
+    #[test]
+    fn test_sharded_queue_order() {
+        let mut rng = thread_rng();
+        let q = ShardedQueue::with_capacity(QueueMode::Lru, 8, 4);
+        for i in 0..8 {
+            assert!(!q.insert(i, &mut rng));
+        }
+
+        /* Taking the shards in turn keeps the global order. */
+        let got: Vec<usize> =
+            (0..10).map(|_| q.get(&mut rng).unwrap()).collect();
+        assert_eq!(got, vec![0, 1, 2, 3, 4, 5, 6, 7, 0, 1]);
+
+        /* Each shard holds 2, so a full queue drops items, warning once. */
+        assert!(q.insert(8, &mut rng));
+        assert!(!q.insert(9, &mut rng));
+        assert_eq!(q.dropped.load(Ordering::SeqCst), 2);
+
+        /* The shards hold no more than the cap between them. */
+        for &(cap, shards) in &[(10, 4), (2, 4)] {
+            let q = ShardedQueue::with_capacity(QueueMode::Rand, cap, shards);
+            for i in 0..20 {
+                q.insert(i, &mut rng);
+            }
+            assert_eq!(q.shards.len(), shards.min(cap));
+            assert_eq!(std::iter::from_fn(|| q.remove(&mut rng)).count(), cap);
+        }
+
+        let q = ShardedQueue::new(QueueMode::Mru, 3);
+        for i in 0..5 {
+            q.insert(i, &mut rng);
+        }
+        assert_eq!(q.get(&mut rng), Some(4));
+        let got: Vec<usize> =
+            std::iter::from_fn(|| q.remove(&mut rng)).collect();
+        assert_eq!(got, vec![4, 3, 2, 1, 0]);
+        assert!(q.is_empty());
+
+        /* Empty shards are skipped over. */
+        q.insert(5, &mut rng);
+        q.insert(6, &mut rng);
+        assert_eq!(q.remove(&mut rng), Some(6));
+        assert_eq!(q.remove(&mut rng), Some(5));
+        assert_eq!(q.remove(&mut rng), None);
+    }
+
+    /*
+     * Like the ones above, this benchmark is ignored unless asked for. A
+     * number of threads share a queue, each doing a mix of writes, reads and
+     * deletes, first with the queue behind one lock and then split into 8
+     * shards. With enough cores the sharded queue should keep up as threads
+     * are added, while the single lock falls behind. This only times the
+     * queue, not workers talking to a target. Again, run with
+     * `cargo test -- --ignored --nocapture` to see the numbers.
+     */
+    #[test]
+    #[ignore]
+    fn bench_sharded_queue_scaling() {
+        const OPS: usize = 400_000;
+
+        for &shards in &[1, 8] {
+            for &nthreads in &[1, 2, 4, 8] {
+                let q = Arc::new(ShardedQueue::new(QueueMode::Rand, shards));
+                let start = Instant::now();
+                let threads: Vec<_> = (0..nthreads)
+                    .map(|t| {
+                        let q = q.clone();
+                        thread::spawn(move || {
+                            let mut rng = thread_rng();
+                            let mut removed = 0;
+                            for i in 0..OPS / nthreads {
+                                match i % 4 {
+                                    0 | 1 => {
+                                        q.insert(t * OPS + i, &mut rng);
+                                    }
+                                    2 => {
+                                        q.get(&mut rng);
+                                    }
+                                    _ => {
+                                        if q.remove(&mut rng).is_some() {
+                                            removed += 1;
+                                        }
+                                    }
+                                }
+                            }
+                            removed
+                        })
+                    })
+                    .collect();
+                let removed: usize =
+                    threads.into_iter().map(|t| t.join().unwrap()).sum();
+                let elapsed = start.elapsed();
+
+                println!(
+                    "{} threads, {} shards: {:.0} ops/s",
+                    nthreads,
+                    shards,
+                    (OPS / nthreads * nthreads) as f64 / elapsed.as_secs_f64()
+                );
+
+                /* Every item is still there or was removed exactly once. */
+                let mut left = 0;
+                while q.remove(&mut thread_rng()).is_some() {
+                    left += 1;
+                }
+                assert_eq!(left + removed, OPS / nthreads * nthreads / 2);
+            }
         }
-        let end = start.elapsed().as_millis();
-        println!("removing {} items took {}ms", DEF_QUEUE_CAP, end);
     }
 }
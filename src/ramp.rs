@@ -0,0 +1,429 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use regex::Regex;
+use serde_json::json;
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::utils::{ops_to_json, unix_time, ChumError, OutputFormat, RunStats};
+use crate::worker::{Operation, WorkerStat, PERCENTILES};
+
+/*
+ * A latency objective for the ramp, like 'p99:50ms'. The ramp stops at the
+ * first step whose round trip time at the given percentile (or 'max') is over
+ * the limit. The latency of every operation type is counted.
+ */
+#[derive(Debug, PartialEq)]
+pub struct Slo {
+    name: String,
+    index: usize, /* into WorkerStat::percentiles() */
+    limit: Duration,
+}
+
+impl FromStr for Slo {
+    type Err = ChumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let names: Vec<&str> = PERCENTILES
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(std::iter::once("max"))
+            .collect();
+
+        let tok: Vec<&str> = s.split(':').collect();
+        if tok.len() != 2 {
+            return Err(ChumError::new(
+                "latency objective must look like '<percentile>:<limit>' \
+                 (e.g. 'p99:50ms')",
+            ));
+        }
+        let index = match names.iter().position(|n| *n == tok[0]) {
+            Some(i) => i,
+            None => {
+                return Err(ChumError::new(&format!(
+                    "latency objective percentile must be one of {}",
+                    names.join(", ")
+                )))
+            }
+        };
+
+        let lat_re = Regex::new(r"^(\d+(?:\.\d+)?)(us|ms|s)$").unwrap();
+        let caps = match lat_re.captures(tok[1]) {
+            Some(caps) => caps,
+            None => {
+                return Err(ChumError::new(
+                    "latency objective limit must be a number with a unit \
+                     suffix (us, ms, s)",
+                ))
+            }
+        };
+        let num = caps[1]
+            .parse::<f64>()
+            .map_err(|err| ChumError::new(&err.to_string()))?;
+        let secs = match &caps[2] {
+            "us" => num / 1_000_000.0,
+            "ms" => num / 1000.0,
+            _ => num,
+        };
+
+        Ok(Slo {
+            name: tok[0].to_string(),
+            index,
+            limit: Duration::from_secs_f64(secs),
+        })
+    }
+}
+
+/*
+ * Parse a list of worker counts like:
+ *   1,2,4,8
+ * A '...' continues the steps before it up to the step after it, so this is
+ * the same as above:
+ *   1,2,...,8
+ * Three steps doubling (or otherwise multiplying) continue geometrically:
+ *   1,2,4,...,256
+ * Otherwise the steps continue with the same difference as the last two.
+ */
+pub fn parse_ramp_steps(val: &str) -> Result<Vec<u32>, ChumError> {
+    let mut steps: Vec<u32> = Vec::new();
+    let mut fill = false;
+
+    for tok in val.split(',') {
+        if tok == "..." {
+            if fill || steps.len() < 2 {
+                return Err(ChumError::new(
+                    "'...' must follow at least two ramp steps",
+                ));
+            }
+            fill = true;
+            continue;
+        }
+
+        let step = match tok.parse::<u32>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                return Err(ChumError::new(&format!(
+                    "ramp step '{}' must be a positive number",
+                    tok
+                )))
+            }
+        };
+        if let Some(last) = steps.last() {
+            if step <= *last {
+                return Err(ChumError::new("ramp steps must increase"));
+            }
+        }
+
+        if fill {
+            let n = steps.len();
+            let (a, b) = (steps[n - 2], steps[n - 1]);
+            let geometric = n >= 3 && {
+                let z = steps[n - 3];
+                a % z == 0 && b % a == 0 && a / z == b / a
+            };
+            let mut next = if geometric { b * (b / a) } else { b + (b - a) };
+            while next < step {
+                steps.push(next);
+                next = if geometric {
+                    next * (b / a)
+                } else {
+                    next + (b - a)
+                };
+            }
+            fill = false;
+        }
+        steps.push(step);
+    }
+
+    if fill {
+        return Err(ChumError::new(
+            "'...' must be followed by a final ramp step",
+        ));
+    }
+
+    Ok(steps)
+}
+
+pub struct RampOptions {
+    pub steps: Vec<u32>,
+    pub hold: Duration,   /* how long each step runs */
+    pub plateau: f64,     /* percent improvement needed to keep going */
+    pub slo: Option<Slo>, /* latency objective */
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RampResult {
+    Complete,
+    Plateau,
+    SloBreached,
+    Interrupted,
+}
+
+impl std::fmt::Display for RampResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let str = match self {
+            RampResult::Complete => "complete",
+            RampResult::Plateau => "plateau",
+            RampResult::SloBreached => "slo",
+            RampResult::Interrupted => "interrupted",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+/* What a step of the ramp achieved. */
+struct Step {
+    concurrency: u32,
+    ops_per_sec: f64,
+    bytes_per_sec: f64,
+    latency: WorkerStat, /* every operation type merged */
+}
+
+impl Step {
+    fn new(concurrency: u32, stats: &RunStats) -> Step {
+        let mut latency = WorkerStat::new();
+        for (op, stat) in stats.ops.iter() {
+            if *op != Operation::Error {
+                latency.merge(stat);
+            }
+        }
+
+        let secs = stats.elapsed.as_secs_f64().max(f64::EPSILON);
+        Step {
+            concurrency,
+            ops_per_sec: latency.objs as f64 / secs,
+            bytes_per_sec: latency.data as f64 / secs,
+            latency,
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "concurrency": self.concurrency,
+            "ops_per_sec": self.ops_per_sec,
+            "bytes_per_sec": self.bytes_per_sec,
+            "rtt_ms": WorkerStat::percentiles_to_json(&self.latency.rtt_hist),
+        })
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "{:.3} ops/s, {:.3}MB/s, rtt {}",
+            self.ops_per_sec,
+            self.bytes_per_sec / 1024.0 / 1024.0,
+            WorkerStat::serialize_percentiles(&self.latency.rtt_hist)
+        )
+    }
+}
+
+/*
+ * Run each step of the ramp in turn, using 'run_step' to run the given step
+ * with the given number of workers for the given time. The ramp ends early
+ * when throughput stops improving by at least the plateau percentage over the
+ * best step so far, or when a step misses the latency objective.
+ *
+ * The best step that met the latency objective is reported as the peak.
+ */
+pub fn ramp<F>(opts: &RampOptions, format: &OutputFormat, mut run_step: F)
+where
+    F: FnMut(usize, u32, Duration) -> RunStats,
+{
+    let mut steps: Vec<Step> = Vec::new();
+    let mut peak: Option<usize> = None;
+    let mut result = RampResult::Complete;
+
+    for (i, conc) in opts.steps.iter().enumerate() {
+        let stats = run_step(i, *conc, opts.hold);
+        let step = Step::new(*conc, &stats);
+        print_step(format, &step, &stats);
+        steps.push(step);
+        let step = steps.last().unwrap();
+
+        if stats.interrupted {
+            result = RampResult::Interrupted;
+            break;
+        }
+
+        if let Some(slo) = &opts.slo {
+            let rtt = WorkerStat::percentiles(&step.latency.rtt_hist);
+            if rtt[slo.index] > slo.limit.as_micros() as u64 {
+                result = RampResult::SloBreached;
+                break;
+            }
+        }
+
+        match peak.map(|p| steps[p].ops_per_sec) {
+            None => peak = Some(steps.len() - 1),
+            Some(best) => {
+                if step.ops_per_sec > best {
+                    peak = Some(steps.len() - 1);
+                }
+                if step.ops_per_sec < best * (1.0 + opts.plateau / 100.0) {
+                    result = RampResult::Plateau;
+                    break;
+                }
+            }
+        }
+    }
+
+    print_ramp(format, opts, &steps, peak, result);
+}
+
+fn print_step(format: &OutputFormat, step: &Step, stats: &RunStats) {
+    match format {
+        OutputFormat::Human | OutputFormat::HumanVerbose => {
+            println!("===");
+            println!(
+                "Step ({} threads)\t{}",
+                step.concurrency,
+                step.serialize()
+            );
+        }
+        OutputFormat::Json | OutputFormat::JsonVerbose => {
+            let mut obj = step.to_json();
+            obj["type"] = json!("step");
+            obj["time"] = json!(unix_time());
+            obj["elapsed"] = json!(stats.elapsed.as_secs_f64());
+            obj["total"] = ops_to_json(&stats.ops, Some(stats.start_time));
+            println!("{}", obj);
+        }
+        /* Tabular and CSV output have fixed columns. */
+        OutputFormat::Tabular | OutputFormat::Csv => (),
+    }
+}
+
+fn print_ramp(
+    format: &OutputFormat,
+    opts: &RampOptions,
+    steps: &[Step],
+    peak: Option<usize>,
+    result: RampResult,
+) {
+    match format {
+        OutputFormat::Human | OutputFormat::HumanVerbose => {
+            let why = match (result, &opts.slo) {
+                (RampResult::Complete, _) => "all steps run".to_string(),
+                (RampResult::Plateau, _) => "throughput plateaued".to_string(),
+                (RampResult::SloBreached, Some(slo)) => format!(
+                    "{} latency over {:.3}ms",
+                    slo.name,
+                    slo.limit.as_secs_f64() * 1000.0
+                ),
+                (RampResult::SloBreached, None) => unreachable!(),
+                (RampResult::Interrupted, _) => "interrupted".to_string(),
+            };
+            println!("===");
+            println!("Ramp ({})", why);
+            for step in steps {
+                println!(
+                    "Step ({} threads)\t{}",
+                    step.concurrency,
+                    step.serialize()
+                );
+            }
+            match peak {
+                Some(p) => println!(
+                    "Peak\t{:.3} ops/s at {} threads",
+                    steps[p].ops_per_sec, steps[p].concurrency
+                ),
+                None => println!("Peak\tno step met the latency objective"),
+            }
+        }
+        OutputFormat::Json | OutputFormat::JsonVerbose => {
+            let steps: Vec<serde_json::Value> =
+                steps.iter().map(|s| s.to_json()).collect();
+            println!(
+                "{}",
+                json!({
+                    "type": "ramp",
+                    "time": unix_time(),
+                    "result": result.to_string(),
+                    "peak": peak.map(|p| steps[p].clone()),
+                    "steps": steps,
+                })
+            );
+        }
+        OutputFormat::Tabular | OutputFormat::Csv => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ramp_steps() -> Result<(), ChumError> {
+        assert_eq!(parse_ramp_steps("1,2,4,8")?, vec![1, 2, 4, 8]);
+        assert_eq!(
+            parse_ramp_steps("1,2,4,...,256")?,
+            vec![1, 2, 4, 8, 16, 32, 64, 128, 256]
+        );
+        assert_eq!(
+            parse_ramp_steps("1,3,9,...,100")?,
+            vec![1, 3, 9, 27, 81, 100]
+        );
+        assert_eq!(parse_ramp_steps("10,20,...,50")?, vec![10, 20, 30, 40, 50]);
+        assert_eq!(parse_ramp_steps("1,2,...,4,8")?, vec![1, 2, 3, 4, 8]);
+        assert_eq!(parse_ramp_steps("2,3,5,...,9")?, vec![2, 3, 5, 7, 9]);
+
+        assert_eq!(
+            parse_ramp_steps("1,...,8"),
+            Err(ChumError::new("'...' must follow at least two ramp steps"))
+        );
+        assert_eq!(
+            parse_ramp_steps("1,2,..."),
+            Err(ChumError::new(
+                "'...' must be followed by a final ramp step"
+            ))
+        );
+        assert_eq!(
+            parse_ramp_steps("4,2"),
+            Err(ChumError::new("ramp steps must increase"))
+        );
+        assert_eq!(
+            parse_ramp_steps("0,1"),
+            Err(ChumError::new("ramp step '0' must be a positive number"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_slo_parse() -> Result<(), ChumError> {
+        assert_eq!(
+            "p99:50ms".parse::<Slo>()?,
+            Slo {
+                name: "p99".to_string(),
+                index: 2,
+                limit: Duration::from_millis(50),
+            }
+        );
+        assert_eq!(
+            "max:1.5s".parse::<Slo>()?.limit,
+            Duration::from_millis(1500)
+        );
+        assert_eq!(
+            "p50:250us".parse::<Slo>()?.limit,
+            Duration::from_micros(250)
+        );
+
+        assert_eq!(
+            "p95:50ms".parse::<Slo>(),
+            Err(ChumError::new(
+                "latency objective percentile must be one of p50, p90, p99, \
+                 p99.9, max"
+            ))
+        );
+        assert!("p99:50".parse::<Slo>().is_err());
+        assert!("p99".parse::<Slo>().is_err());
+
+        Ok(())
+    }
+}
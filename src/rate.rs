@@ -0,0 +1,290 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use rand::distributions::Exp1;
+use rand::rngs::StdRng;
+use rand::Rng;
+use regex::Regex;
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::utils::{sleep_unless_stopped, ChumError};
+
+/* An offered load, per second. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rate {
+    Ops(f64),
+    Bytes(f64),
+}
+
+impl FromStr for Rate {
+    type Err = ChumError;
+
+    /*
+     * Parse a rate like '2000ops/s' or '500MB/s'. Byte units are powers of
+     * 1024, like everywhere else in chum.
+     */
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rate_re =
+            Regex::new(r"^(\d+(?:\.\d+)?)((?i)ops|[kmgt]?b)/s$").unwrap();
+        let caps = match rate_re.captures(s) {
+            Some(caps) => caps,
+            None => {
+                return Err(ChumError::new(
+                    "provided rate must be a positive number of ops/s or \
+                     bytes/s (e.g. '2000ops/s', '500MB/s')",
+                ))
+            }
+        };
+
+        let num = caps[1]
+            .parse::<f64>()
+            .map_err(|err| ChumError::new(&err.to_string()))?;
+        if num <= 0.0 {
+            return Err(ChumError::new("provided rate must be non-zero"));
+        }
+
+        let unit = caps[2].to_ascii_lowercase();
+        let mult = match unit.as_ref() {
+            "ops" => return Ok(Rate::Ops(num)),
+            "b" => 1u64,
+            "kb" => 1024,
+            "mb" => 1024 * 1024,
+            "gb" => 1024 * 1024 * 1024,
+            "tb" => 1024 * 1024 * 1024 * 1024,
+            _ => return Err(ChumError::new("unrecognized unit suffix")),
+        };
+        Ok(Rate::Bytes(num * mult as f64))
+    }
+}
+
+/*
+ * How operation start times are spaced. Constant arrivals are evenly spaced.
+ * Poisson arrivals have exponentially distributed gaps with the same mean,
+ * which is closer to what a server sees from many independent clients.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arrival {
+    Constant,
+    Poisson,
+}
+
+impl FromStr for Arrival {
+    type Err = ChumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "constant" => Ok(Arrival::Constant),
+            "poisson" => Ok(Arrival::Poisson),
+            _ => Err(ChumError::new("invalid arrival schedule requested")),
+        }
+    }
+}
+
+impl fmt::Display for Arrival {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arrival::Constant => write!(f, "constant"),
+            Arrival::Poisson => write!(f, "poisson"),
+        }
+    }
+}
+
+/*
+ * A Pacer hands out operation start times to every worker thread, so that
+ * together they offer a fixed load no matter how quickly the target responds.
+ *
+ * Each start time is claimed by exactly one worker. When the target can't
+ * keep up the start times fall behind the clock and workers run back to back.
+ * Latency is measured from the claimed start time rather than from when the
+ * worker got around to the operation. Otherwise a slow target would delay the
+ * operations that would have seen its slowness, hiding it from the results
+ * (coordinated omission).
+ *
+ * Operation rates space the start times out as they're claimed. Byte rates
+ * can't, since the size of a read isn't known until it's done. Instead each
+ * operation pushes later start times back once it completes, which allows
+ * bursts of up to one operation per worker.
+ *
+ * Poisson gaps are drawn from the pacer's own generator, which is seeded like
+ * the workers' when the run is seeded.
+ */
+pub struct Pacer {
+    rate: Rate,
+    arrival: Arrival,
+    next: Mutex<Instant>,
+    rng: Mutex<StdRng>,
+}
+
+impl Pacer {
+    pub fn new(rate: Rate, arrival: Arrival, rng: StdRng) -> Pacer {
+        Pacer {
+            rate,
+            arrival,
+            next: Mutex::new(Instant::now()),
+            rng: Mutex::new(rng),
+        }
+    }
+
+    /* The time taken by 'amount' ops or bytes at the target rate. */
+    fn gap(&self, amount: f64, per_sec: f64) -> Duration {
+        let mut secs = amount / per_sec;
+        if self.arrival == Arrival::Poisson {
+            secs *= self.rng.lock().unwrap().sample::<f64, _>(Exp1);
+        }
+        Duration::from_secs_f64(secs)
+    }
+
+    /*
+     * Claim the next start time and sleep until it arrives, or until the run
+     * is stopped. Returns the claimed start time.
+     */
+    pub fn wait(&self, stop: &AtomicBool) -> Instant {
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = *next;
+            if let Rate::Ops(per_sec) = self.rate {
+                *next += self.gap(1.0, per_sec);
+            }
+            start
+        };
+
+        sleep_unless_stopped(
+            start.saturating_duration_since(Instant::now()),
+            &[stop],
+        );
+        start
+    }
+
+    /*
+     * Start handing out start times from now. Otherwise the time between two
+     * runs would be made up for with a burst of operations.
+     */
+    pub fn reset(&self) {
+        *self.next.lock().unwrap() = Instant::now();
+    }
+
+    /* Account for the bytes moved by a completed operation. */
+    pub fn charge(&self, bytes: u64) {
+        if let Rate::Bytes(per_sec) = self.rate {
+            let gap = self.gap(bytes as f64, per_sec);
+            *self.next.lock().unwrap() += gap;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_rate_parse() -> Result<(), ChumError> {
+        assert_eq!("2000ops/s".parse::<Rate>()?, Rate::Ops(2000.0));
+        assert_eq!("0.5OPS/s".parse::<Rate>()?, Rate::Ops(0.5));
+        assert_eq!("100b/s".parse::<Rate>()?, Rate::Bytes(100.0));
+        assert_eq!("500MB/s".parse::<Rate>()?, Rate::Bytes(524_288_000.0));
+        assert_eq!("2kb/s".parse::<Rate>()?, Rate::Bytes(2048.0));
+
+        assert_eq!(
+            "0ops/s".parse::<Rate>(),
+            Err(ChumError::new("provided rate must be non-zero"))
+        );
+        for bad in &["", "2000", "ops/s", "-1ops/s", "5MB", "5xb/s"] {
+            assert_eq!(
+                bad.parse::<Rate>(),
+                Err(ChumError::new(
+                    "provided rate must be a positive number of ops/s or \
+                     bytes/s (e.g. '2000ops/s', '500MB/s')"
+                ))
+            );
+        }
+
+        assert_eq!("poisson".parse::<Arrival>()?, Arrival::Poisson);
+        assert!("bursty".parse::<Arrival>().is_err());
+
+        Ok(())
+    }
+
+    /* Time taken to claim 'n' start times, charging 'bytes' for each. */
+    fn pace(pacer: &Pacer, n: usize, bytes: u64) -> Duration {
+        let stop = AtomicBool::new(false);
+        let begin = Instant::now();
+        for _ in 0..n {
+            pacer.wait(&stop);
+            pacer.charge(bytes);
+        }
+        begin.elapsed()
+    }
+
+    /*
+     * Each pacer below should take about 200ms. The upper bounds leave plenty
+     * of room, since a busy machine can be slow to wake a sleeping thread.
+     */
+    #[test]
+    fn test_pacer() {
+        let pacer = Pacer::new(
+            Rate::Ops(1000.0),
+            Arrival::Constant,
+            StdRng::seed_from_u64(1),
+        );
+        let elapsed = pace(&pacer, 201, 0);
+        assert!(
+            elapsed >= Duration::from_millis(200)
+                && elapsed < Duration::from_secs(2),
+            "constant ops took {:?}",
+            elapsed
+        );
+
+        let pacer = Pacer::new(
+            Rate::Bytes(1024.0 * 1024.0),
+            Arrival::Constant,
+            StdRng::seed_from_u64(1),
+        );
+        let elapsed = pace(&pacer, 21, 10 * 1024);
+        assert!(
+            elapsed >= Duration::from_millis(190)
+                && elapsed < Duration::from_secs(2),
+            "constant bytes took {:?}",
+            elapsed
+        );
+
+        /*
+         * Poisson gaps average out to the same rate. The seed keeps the gaps
+         * the same from one run to the next.
+         */
+        let pacer = Pacer::new(
+            Rate::Ops(5000.0),
+            Arrival::Poisson,
+            StdRng::seed_from_u64(1),
+        );
+        let elapsed = pace(&pacer, 1001, 0);
+        assert!(
+            elapsed >= Duration::from_millis(120)
+                && elapsed < Duration::from_secs(2),
+            "poisson ops took {:?}",
+            elapsed
+        );
+
+        /* Stopping the run cuts the wait short. */
+        let pacer = Pacer::new(
+            Rate::Ops(0.1),
+            Arrival::Constant,
+            StdRng::seed_from_u64(1),
+        );
+        let stop = AtomicBool::new(true);
+        pacer.wait(&stop);
+        let begin = Instant::now();
+        pacer.wait(&stop);
+        assert!(begin.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,468 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::queue::Aged;
+use crate::utils::{ChumError, ErrorKind};
+
+/* Stand-ins for fields that aren't known, e.g. for bare names. */
+const NO_SIZE: u64 = u64::MAX;
+const NO_TIME: u64 = 0;
+
+/*
+ * An object's name. Chum names objects with UUIDs, which are kept as their 16
+ * bytes. Anything else, e.g. from a hand-made listing, is kept as is.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum ObjectName {
+    Uuid(Uuid),
+    Other(Box<str>),
+}
+
+impl From<&str> for ObjectName {
+    fn from(name: &str) -> ObjectName {
+        match Uuid::parse_str(name) {
+            /* Only names that print back the same way can be shrunk. */
+            Ok(id) if id.to_string() == name => ObjectName::Uuid(id),
+            _ => ObjectName::Other(name.into()),
+        }
+    }
+}
+
+impl fmt::Display for ObjectName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjectName::Uuid(id) => write!(f, "{}", id),
+            ObjectName::Other(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/*
+ * What the target returned to identify an object's contents. S3 ETags for
+ * objects written in one request are the quoted MD5 of the data, which is kept
+ * as its 16 bytes.
+ */
+#[derive(Clone, Debug, PartialEq)]
+enum Checksum {
+    Md5([u8; 16]),
+    Other(Box<str>),
+}
+
+impl From<&str> for Checksum {
+    fn from(s: &str) -> Checksum {
+        let hex = s.trim_start_matches('"').trim_end_matches('"');
+        if s.len() != 34 || hex.len() != 32 || !hex.is_ascii() {
+            return Checksum::Other(s.into());
+        }
+
+        let mut md5 = [0u8; 16];
+        for (i, b) in md5.iter_mut().enumerate() {
+            match u8::from_str_radix(&hex[2 * i..2 * i + 2], 16) {
+                Ok(v) => *b = v,
+                Err(_) => return Checksum::Other(s.into()),
+            }
+        }
+        Checksum::Md5(md5)
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Checksum::Md5(md5) => {
+                write!(f, "\"")?;
+                for b in md5.iter() {
+                    write!(f, "{:02x}", b)?;
+                }
+                write!(f, "\"")
+            }
+            Checksum::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/*
+ * What chum knows about an object it wrote. Workers keep one of these in the
+ * read queue for every object, and reads check the object against it.
+ *
+ * A long soak can keep a hundred million of these, so they're kept small:
+ * about 80 bytes each. UUID names and MD5 checksums are kept as bytes, and the
+ * directory of an fs object is shared with every other object in it.
+ *
+ * Objects listed with '--read-list' may be bare names, so everything but the
+ * name is optional.
+ *
+ * Listings hold one object per line, either a bare name or a JSON object:
+ *   {"name": "...", "size": 4096, "written": 1590000000.123,
+ *    "checksum": "\"9a0364b9e99bb480dd25e1f0284c8555\"", "backend": "s3"}
+ *
+ * 'written' is in seconds since the epoch. 'backend' is the protocol of the
+ * run that wrote the listing, and is only there for people reading it. See
+ * ListEntry for objects that were deleted.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectRecord {
+    dir: Option<Arc<str>>, /* everything up to the last '/' in the name */
+    name: ObjectName,
+    size: u64,    /* or NO_SIZE */
+    written: u64, /* milliseconds since the epoch, or NO_TIME */
+    checksum: Option<Checksum>,
+}
+
+fn unix_millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(NO_TIME)
+}
+
+impl ObjectRecord {
+    /* A record for an object that was just written. */
+    pub fn new(dir: Option<Arc<str>>, id: Uuid, size: u64) -> ObjectRecord {
+        ObjectRecord {
+            dir,
+            name: ObjectName::Uuid(id),
+            size,
+            written: unix_millis(SystemTime::now()),
+            checksum: None,
+        }
+    }
+
+    /* A record with nothing but a name, as in a bare listing. */
+    pub fn bare(name: &str) -> ObjectRecord {
+        let (dir, name) = match name.rfind('/') {
+            Some(i) => (Some(name[..=i].into()), &name[i + 1..]),
+            None => (None, name),
+        };
+        ObjectRecord {
+            dir,
+            name: name.into(),
+            size: NO_SIZE,
+            written: NO_TIME,
+            checksum: None,
+        }
+    }
+
+    /* The full name, including the directory if there is one. */
+    pub fn name(&self) -> String {
+        match &self.dir {
+            Some(dir) => format!("{}{}", dir, self.name),
+            None => self.name.to_string(),
+        }
+    }
+
+    /* The name without the directory. */
+    pub fn file_name(&self) -> String {
+        self.name.to_string()
+    }
+
+    pub fn size(&self) -> Option<u64> {
+        Some(self.size).filter(|s| *s != NO_SIZE)
+    }
+
+    pub fn set_checksum(&mut self, checksum: &str) {
+        self.checksum = Some(checksum.into());
+    }
+
+    /*
+     * Share the directory name with the last record that had the same one, so
+     * that a listing of many objects in a few directories stays small.
+     */
+    pub fn share_dir(&mut self, last: &mut Option<Arc<str>>) {
+        if self.dir.is_some() && self.dir == *last {
+            self.dir = last.clone();
+        } else {
+            *last = self.dir.clone();
+        }
+    }
+
+    /* How long ago the object was written, if that's known. */
+    pub fn age(&self) -> Option<Duration> {
+        if self.written == NO_TIME {
+            return None;
+        }
+        let written = UNIX_EPOCH + Duration::from_millis(self.written);
+        Some(
+            SystemTime::now()
+                .duration_since(written)
+                .unwrap_or_default(),
+        )
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut obj = json!({ "name": self.name() });
+        if let Some(size) = self.size() {
+            obj["size"] = json!(size);
+        }
+        if self.written != NO_TIME {
+            obj["written"] = json!(self.written as f64 / 1000.0);
+        }
+        if let Some(checksum) = &self.checksum {
+            obj["checksum"] = json!(checksum.to_string());
+        }
+        obj
+    }
+
+    /*
+     * Check what a read returned against what was written. A different length
+     * means the object was truncated or grew, and a different checksum means
+     * its contents changed.
+     */
+    pub fn check_read(
+        &self,
+        size: u64,
+        checksum: Option<&str>,
+    ) -> Result<(), ChumError> {
+        if let Some(expected) = self.size() {
+            if size != expected {
+                let kind = if size < expected {
+                    ErrorKind::Truncated
+                } else {
+                    ErrorKind::Corrupt
+                };
+                return Err(ChumError::with_kind(
+                    kind,
+                    &format!(
+                        "reading {} failed: expected {} bytes, got {}",
+                        self.name(),
+                        expected,
+                        size
+                    ),
+                ));
+            }
+        }
+
+        if let (Some(expected), Some(got)) = (&self.checksum, checksum) {
+            if *expected != Checksum::from(got) {
+                return Err(ChumError::with_kind(
+                    ErrorKind::Corrupt,
+                    &format!(
+                        "reading {} failed: expected checksum {}, got {}",
+                        self.name(),
+                        expected,
+                        got
+                    ),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Aged for ObjectRecord {
+    fn age(&self) -> Option<Duration> {
+        ObjectRecord::age(self)
+    }
+}
+
+/* One line of a listing. */
+impl fmt::Display for ObjectRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_json())
+    }
+}
+
+impl FromStr for ObjectRecord {
+    type Err = ChumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_line(s).map(|(rec, _)| rec)
+    }
+}
+
+/*
+ * A line of a listing. An object deleted by the run that wrote the listing is
+ * listed a second time, as a tombstone:
+ *   {"name": "...", "deleted": true, "backend": "s3"}
+ */
+#[derive(Debug, PartialEq)]
+pub enum ListEntry {
+    Written(ObjectRecord),
+    Deleted(ObjectRecord),
+}
+
+impl ListEntry {
+    pub fn to_json(&self) -> Value {
+        match self {
+            ListEntry::Written(rec) => rec.to_json(),
+            ListEntry::Deleted(rec) => {
+                json!({ "name": rec.name(), "deleted": true })
+            }
+        }
+    }
+}
+
+impl FromStr for ListEntry {
+    type Err = ChumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_line(s)? {
+            (rec, false) => Ok(ListEntry::Written(rec)),
+            (rec, true) => Ok(ListEntry::Deleted(rec)),
+        }
+    }
+}
+
+/* Parse a line of a listing, returning whether it's a tombstone too. */
+fn parse_line(s: &str) -> Result<(ObjectRecord, bool), ChumError> {
+    if !s.starts_with('{') {
+        return Ok((ObjectRecord::bare(s), false));
+    }
+
+    let bad = || ChumError::new(&format!("malformed object record: {}", s));
+    let val: Value = serde_json::from_str(s).map_err(|_| bad())?;
+    let string = |key: &str| -> Result<Option<&str>, ChumError> {
+        match &val[key] {
+            Value::Null => Ok(None),
+            v => v.as_str().map(Some).ok_or_else(bad),
+        }
+    };
+
+    let mut rec = ObjectRecord::bare(string("name")?.ok_or_else(bad)?);
+    match &val["size"] {
+        Value::Null => (),
+        v => rec.size = v.as_u64().filter(|s| *s != NO_SIZE).ok_or_else(bad)?,
+    }
+    match &val["written"] {
+        Value::Null => (),
+        v => {
+            let secs = v.as_f64().filter(|s| *s > 0.0).ok_or_else(bad)?;
+            rec.written = (secs * 1000.0).round() as u64;
+        }
+    }
+    if let Some(checksum) = string("checksum")? {
+        rec.set_checksum(checksum);
+    }
+    /* The backend is only there for people reading the listing. */
+    string("backend")?;
+
+    let deleted = match &val["deleted"] {
+        Value::Null => false,
+        v => v.as_bool().ok_or_else(bad)?,
+    };
+
+    Ok((rec, deleted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &str = "5e0c8a3c-7f3e-4f0b-9a7e-2d8e1f6a9b10";
+    const ETAG: &str = "\"9a0364b9e99bb480dd25e1f0284c8555\"";
+
+    #[test]
+    fn test_record_roundtrip() -> Result<(), ChumError> {
+        let id = Uuid::parse_str(NAME).unwrap();
+        let mut rec =
+            ObjectRecord::new(Some("/chum/2020/101/0/".into()), id, 4096);
+        rec.set_checksum(ETAG);
+        let got: ObjectRecord = rec.to_string().parse()?;
+        assert_eq!(got, rec);
+        assert_eq!(got.name(), format!("/chum/2020/101/0/{}", NAME));
+        assert_eq!(got.file_name(), NAME);
+        assert_eq!(got.size(), Some(4096));
+        assert_eq!(got.to_json()["checksum"], json!(ETAG));
+
+        /* Bare names from older listings have nothing else to go on. */
+        let bare: ObjectRecord = NAME.parse()?;
+        assert_eq!(bare, ObjectRecord::bare(NAME));
+        assert_eq!(bare.name, ObjectName::Uuid(id));
+        assert_eq!((bare.size(), bare.age()), (None, None));
+        assert!(bare.check_read(1, Some(ETAG)).is_ok());
+
+        /*
+         * Names that aren't UUIDs, or aren't written the usual way, are kept
+         * as they are.
+         */
+        for name in &["notes.txt", "5E0C8A3C-7F3E-4F0B-9A7E-2D8E1F6A9B10"] {
+            let rec = ObjectRecord::bare(name);
+            assert_eq!(rec.name, ObjectName::Other((*name).into()));
+            assert_eq!(rec.name(), *name);
+        }
+
+        assert!("{\"size\": 1}".parse::<ObjectRecord>().is_err());
+        assert!("{\"name\": 1}".parse::<ObjectRecord>().is_err());
+        assert!("{\"name\": \"a\", \"size\": -1}"
+            .parse::<ObjectRecord>()
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_entry() -> Result<(), ChumError> {
+        let rec = ObjectRecord::bare(&format!("/chum/0/{}", NAME));
+        let tomb = ListEntry::Deleted(rec.clone());
+        assert_eq!(
+            tomb.to_json(),
+            json!({ "name": rec.name(), "deleted": true })
+        );
+        assert_eq!(tomb.to_json().to_string().parse::<ListEntry>()?, tomb);
+
+        assert_eq!(
+            NAME.parse::<ListEntry>()?,
+            ListEntry::Written(NAME.parse()?)
+        );
+        let line = "{\"name\": \"a\", \"deleted\": false}";
+        assert_eq!(
+            line.parse::<ListEntry>()?,
+            ListEntry::Written(line.parse()?)
+        );
+        assert!("{\"name\": \"a\", \"deleted\": 1}"
+            .parse::<ListEntry>()
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_size() {
+        assert!(std::mem::size_of::<ObjectRecord>() <= 80);
+
+        /* Objects in the same directory share its name. */
+        let mut last = None;
+        let mut a = ObjectRecord::bare(&format!("/chum/0/{}", NAME));
+        let mut b = ObjectRecord::bare(&format!("/chum/0/{}", NAME));
+        a.share_dir(&mut last);
+        b.share_dir(&mut last);
+        assert!(Arc::ptr_eq(
+            a.dir.as_ref().unwrap(),
+            b.dir.as_ref().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_record_check_read() {
+        let id = Uuid::parse_str(NAME).unwrap();
+        let mut rec = ObjectRecord::new(None, id, 4096);
+        rec.set_checksum(ETAG);
+        let kind = |res: Result<(), ChumError>| res.unwrap_err().kind();
+
+        assert!(rec.check_read(4096, Some(ETAG)).is_ok());
+        assert!(rec.check_read(4096, None).is_ok());
+        assert_eq!(kind(rec.check_read(4095, None)), ErrorKind::Truncated);
+        assert_eq!(kind(rec.check_read(4097, None)), ErrorKind::Corrupt);
+        assert_eq!(
+            kind(rec.check_read(4096, Some("\"abc\""))),
+            ErrorKind::Corrupt
+        );
+
+        /* Checksums that aren't MD5s are compared as they are. */
+        rec.set_checksum("\"abc-2\"");
+        assert!(rec.check_read(4096, Some("\"abc-2\"")).is_ok());
+    }
+}
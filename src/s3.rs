@@ -8,8 +8,7 @@
 
 extern crate uuid;
 
-use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::rngs::StdRng;
 use rand::AsByteSliceMut;
 use rand::Rng;
 
@@ -17,31 +16,59 @@ use std::env;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 use rusoto_core::{Region, RusotoError};
 use rusoto_credential::EnvironmentProvider;
 use rusoto_s3::{
-    CreateBucketRequest, DeleteObjectRequest, GetObjectRequest,
-    PutObjectRequest, S3Client, S3 as S3Trait,
+    CreateBucketRequest, Delete, DeleteObjectRequest, DeleteObjectsRequest,
+    GetObjectRequest, ListObjectsV2Request, ObjectIdentifier, PutObjectRequest,
+    S3Client, S3 as S3Trait,
 };
 
-use uuid::Uuid;
-
+use crate::cleanup::{Cleaner, BATCH_SIZE};
+use crate::record::ObjectRecord;
 use crate::utils::ChumError;
-use crate::worker::{Backend, Operation, WorkerInfo, WorkerOptions, DIR};
+use crate::verify::{Payload, Verifier};
+use crate::worker::{
+    random_uuid, Backend, Operation, WorkerInfo, WorkerOptions, DIR,
+};
+
+/*
+ * Users may supply access keys in environment variables. We use the minio
+ * defaults if keys are not provided.
+ */
+fn new_client(target: &str) -> S3Client {
+    if env::var("AWS_ACCESS_KEY_ID").is_err() {
+        env::set_var("AWS_ACCESS_KEY_ID", "minioadmin");
+    }
+    if env::var("AWS_SECRET_ACCESS_KEY").is_err() {
+        env::set_var("AWS_SECRET_ACCESS_KEY", "minioadmin");
+    }
+
+    let region = Region::Custom {
+        name: "chum-s3".to_owned(),
+        endpoint: format!("http://{}", target),
+    };
+
+    S3Client::new_with(
+        rusoto_core::request::HttpClient::new()
+            .expect("failed to create S3 HTTP client"),
+        EnvironmentProvider::default(),
+        region,
+    )
+}
 
 pub struct S3 {
     buf: Vec<u8>,
     client: S3Client,
     wopts: WorkerOptions,
+    rng: StdRng,
 }
 
 impl S3 {
-    pub fn new(wopts: WorkerOptions) -> S3 {
-        let mut rng = thread_rng();
-
+    pub fn new(wopts: WorkerOptions, mut rng: StdRng) -> S3 {
         /*
          * Create a random buffer. This is the data that will be sent
          * to the target server.
@@ -52,33 +79,11 @@ impl S3 {
         let mut vec: Vec<u8> = Vec::new();
         vec.extend_from_slice(arr);
 
-        /*
-         * Users may supply access keys in environment variables. We use
-         * the minio defaults if keys are not provided.
-         */
-        if env::var("AWS_ACCESS_KEY_ID").is_err() {
-            env::set_var("AWS_ACCESS_KEY_ID", "minioadmin");
-        }
-        if env::var("AWS_SECRET_ACCESS_KEY").is_err() {
-            env::set_var("AWS_SECRET_ACCESS_KEY", "minioadmin");
-        }
-
-        let region = Region::Custom {
-            name: "chum-s3".to_owned(),
-            endpoint: format!("http://{}", wopts.target),
-        };
-
-        let client: S3Client = S3Client::new_with(
-            rusoto_core::request::HttpClient::new()
-                .expect("failed to create S3 HTTP client"),
-            EnvironmentProvider::default(),
-            region,
-        );
-
         let mut s3 = S3 {
             buf: vec,
-            client,
+            client: new_client(&wopts.target),
             wopts,
+            rng,
         };
 
         s3.setup();
@@ -109,31 +114,31 @@ impl S3 {
 impl Backend for S3 {
     fn write(&mut self) -> Result<Option<WorkerInfo>, ChumError> {
         /* This should be similar to how muskie generates objectids. */
-        let fname = Uuid::new_v4();
-
-        let mut rng = thread_rng();
-        let size = *self
-            .wopts
-            .distribution
-            .choose(&mut rng)
-            .expect("choosing file size failed");
+        let fname = random_uuid(&mut self.rng);
+        let size = self.wopts.distribution.sample(&mut self.rng);
 
         /*
          * The S3 client library that we're using doesn't have simply
          * sync-friendly buffered IO support. Here we just create one giant
          * buffer to send along.
          */
-        let mut buf: Vec<u8> = Vec::with_capacity(size as usize);
-        let mut bytes_to_go = size;
-        while bytes_to_go > 0 {
-            if bytes_to_go < self.buf.len() as u64 {
-                let tail = &self.buf[0..(bytes_to_go - 1) as usize];
-                buf.extend(tail);
-                break;
+        let buf = if self.wopts.verify {
+            Payload::new(&fname.to_string(), size, self.wopts.payload_seed())
+                .into_vec()
+        } else {
+            let mut buf: Vec<u8> = Vec::with_capacity(size as usize);
+            let mut bytes_to_go = size;
+            while bytes_to_go > 0 {
+                if bytes_to_go < self.buf.len() as u64 {
+                    let tail = &self.buf[0..bytes_to_go as usize];
+                    buf.extend(tail);
+                    break;
+                }
+                buf.extend(&self.buf);
+                bytes_to_go -= self.buf.len() as u64;
             }
-            buf.extend(&self.buf);
-            bytes_to_go -= self.buf.len() as u64;
-        }
+            buf
+        };
 
         let full_path = self.get_path(fname.to_string());
 
@@ -153,40 +158,32 @@ impl Backend for S3 {
          */
         match self.client.put_object(pr).sync() {
             Err(e) => Err(ChumError::new(&e.to_string())),
-            Ok(_) => {
-                if self.wopts.read_queue {
-                    self.wopts.queue.lock().unwrap().insert(fname.to_string());
+            Ok(res) => {
+                let mut rec = ObjectRecord::new(None, fname, size);
+                if let Some(etag) = &res.e_tag {
+                    rec.set_checksum(etag);
                 }
+                self.wopts.written(rec, &mut self.rng);
 
-                let rtt = rtt_start.elapsed().as_millis();
+                let rtt = rtt_start.elapsed();
                 Ok(Some(WorkerInfo {
                     id: thread::current().id(),
                     op: Operation::Write,
                     size,
-                    ttfb: 0, /* not supported */
+                    ttfb: Duration::from_secs(0), /* not supported */
                     rtt,
+                    age: None,
                 }))
             }
         }
     }
 
     fn read(&mut self) -> Result<Option<WorkerInfo>, ChumError> {
-        /*
-         * Create a scope here to ensure that we don't keep the queue locked
-         * for longer than necessary.
-         */
-        let full_path: String;
-        {
-            let mut q = self.wopts.queue.lock().unwrap();
-            let qi = q.get();
-            if qi.is_none() {
-                return Ok(None);
-            }
-            let qi = qi.unwrap();
-
-            let fname = qi.clone();
-            full_path = self.get_path(fname).to_str().unwrap().to_string();
-        }
+        let rec = match self.wopts.queue.get(&mut self.rng) {
+            Some(rec) => rec,
+            None => return Ok(None),
+        };
+        let full_path = self.get_path(rec.name()).to_str().unwrap().to_string();
 
         let gr = GetObjectRequest {
             bucket: DIR.to_string(),
@@ -204,12 +201,12 @@ impl Backend for S3 {
         }?;
 
         /*
-         * Read the response buffer and throw it away. We don't care about the
-         * data.
+         * Read the response buffer. Unless we're verifying the data we throw
+         * it away.
          */
+        let mut body = Vec::new();
         if res.body.is_some() {
             let mut stream = res.body.unwrap().into_blocking_read();
-            let mut body = Vec::new();
             stream.read_to_end(&mut body).expect(
                 "failed to read response \
                  body",
@@ -217,34 +214,32 @@ impl Backend for S3 {
         }
 
         let size = res.content_length.expect("failed to get content-length");
-        let rtt = rtt_start.elapsed().as_millis();
+        let rtt = rtt_start.elapsed();
+
+        if self.wopts.verify {
+            let mut verifier =
+                Verifier::new(&rec.name(), self.wopts.payload_seed());
+            verifier.update(&body);
+            verifier.finish()?;
+        }
+        rec.check_read(size as u64, res.e_tag.as_deref())?;
 
         Ok(Some(WorkerInfo {
             id: thread::current().id(),
             op: Operation::Read,
             size: size as u64,
-            ttfb: 0,
+            ttfb: Duration::from_secs(0),
             rtt,
+            age: rec.age(),
         }))
     }
 
     fn delete(&mut self) -> Result<Option<WorkerInfo>, ChumError> {
-        let full_path: String;
-        let fname: String;
-        {
-            let mut q = self.wopts.queue.lock().unwrap();
-            let qi = q.remove();
-            if qi.is_none() {
-                return Ok(None);
-            }
-            fname = qi.unwrap();
-
-            full_path = self
-                .get_path(fname.to_string())
-                .to_str()
-                .unwrap()
-                .to_string();
-        }
+        let rec = match self.wopts.queue.remove(&mut self.rng) {
+            Some(rec) => rec,
+            None => return Ok(None),
+        };
+        let full_path = self.get_path(rec.name()).to_str().unwrap().to_string();
 
         let dr = DeleteObjectRequest {
             bucket: DIR.to_string(),
@@ -261,7 +256,7 @@ impl Backend for S3 {
          * operations if there was an error during the delete.
          */
         if let Err(e) = res {
-            self.wopts.queue.lock().unwrap().insert(fname);
+            self.wopts.queue.insert(rec, &mut self.rng);
 
             return Err(ChumError::new(&format!(
                 "Deleting {} failed: {}",
@@ -269,14 +264,104 @@ impl Backend for S3 {
             )));
         }
 
-        let rtt = rtt_start.elapsed().as_millis();
+        let rtt = rtt_start.elapsed();
+        self.wopts.deleted(&rec);
 
         Ok(Some(WorkerInfo {
             id: thread::current().id(),
             op: Operation::Delete,
             size: 0,
-            ttfb: 0,
+            ttfb: Duration::from_secs(0),
             rtt,
+            age: rec.age(),
         }))
     }
 }
+
+/* Objects are all under v2/chum/ in the chum bucket. */
+pub struct S3Cleaner {
+    client: S3Client,
+}
+
+impl S3Cleaner {
+    pub fn new(target: &str) -> S3Cleaner {
+        S3Cleaner {
+            client: new_client(target),
+        }
+    }
+}
+
+impl Cleaner for S3Cleaner {
+    fn list(
+        &mut self,
+        found: &mut dyn FnMut(Vec<String>) -> bool,
+    ) -> Result<(), ChumError> {
+        let mut token = None;
+        loop {
+            let req = ListObjectsV2Request {
+                bucket: DIR.to_string(),
+                prefix: Some(format!("v2/{}/", DIR)),
+                continuation_token: token,
+                max_keys: Some(BATCH_SIZE as i64),
+                ..Default::default()
+            };
+            let res = self.client.list_objects_v2(req).sync().map_err(|e| {
+                ChumError::new(&format!("listing objects failed: {}", e))
+            })?;
+
+            let keys: Vec<String> = res
+                .contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|obj| obj.key)
+                .collect();
+            if !keys.is_empty() && !found(keys) {
+                return Ok(());
+            }
+
+            token = res.next_continuation_token;
+            if !res.is_truncated.unwrap_or(false) || token.is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    /* Objects are deleted in bulk, a batch per request. */
+    fn delete(&mut self, objs: &[String]) -> Vec<ChumError> {
+        let req = DeleteObjectsRequest {
+            bucket: DIR.to_string(),
+            delete: Delete {
+                objects: objs
+                    .iter()
+                    .map(|key| ObjectIdentifier {
+                        key: key.clone(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                quiet: Some(true),
+            },
+            ..Default::default()
+        };
+
+        match self.client.delete_objects(req).sync() {
+            Ok(res) => res
+                .errors
+                .unwrap_or_default()
+                .into_iter()
+                .map(|e| {
+                    ChumError::new(&format!(
+                        "Deleting {} failed: {}",
+                        e.key.unwrap_or_default(),
+                        e.message.unwrap_or_default()
+                    ))
+                })
+                .collect(),
+            Err(e) => objs
+                .iter()
+                .map(|key| {
+                    ChumError::new(&format!("Deleting {} failed: {}", key, e))
+                })
+                .collect(),
+        }
+    }
+}
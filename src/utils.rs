@@ -8,19 +8,34 @@
 
 extern crate fs3;
 
+use rand::Rng;
 use regex::Regex;
+use serde_json::json;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt::Debug;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::sync::{mpsc::Receiver, Arc, Mutex};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
 use std::vec::Vec;
-use std::{thread, thread::ThreadId};
-use std::{time, time::SystemTime, time::UNIX_EPOCH};
+use std::{thread, thread::JoinHandle, thread::ThreadId};
+use std::{time, time::Instant, time::SystemTime, time::UNIX_EPOCH};
 
-use crate::queue::Queue;
-use crate::worker::{Operation, WorkerInfo, WorkerStat};
+use crate::budget::OpBudget;
+use crate::queue::ShardedQueue;
+use crate::record::{ListEntry, ObjectRecord};
+use crate::worker::{
+    micros_to_ms, Operation, WorkerInfo, WorkerResult, WorkerStat, PERCENTILES,
+};
+
+/* The stage of a run that isn't part of a larger workload. */
+pub const DEF_STAGE: &str = "run";
+
+/* How often a sleeping stat thread checks whether the run was stopped. */
+const STOP_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
 
 /*
  * In the future we should use multiple '-v' flags for this:
@@ -30,11 +45,14 @@ use crate::worker::{Operation, WorkerInfo, WorkerStat};
  *
  * But today the user specifies the exact format they want.
  */
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum OutputFormat {
     Human, /* prose, for humans watching the console. */
     HumanVerbose,
     Tabular, /* tab-separated, for throwing into something like gnuplot. */
+    Csv,     /* comma-separated, same columns as tabular. */
+    Json,    /* one JSON object per line, for dashboards and scripts. */
+    JsonVerbose,
 }
 
 impl std::str::FromStr for OutputFormat {
@@ -45,18 +63,61 @@ impl std::str::FromStr for OutputFormat {
             "h" => Ok(OutputFormat::Human),
             "v" => Ok(OutputFormat::HumanVerbose),
             "t" => Ok(OutputFormat::Tabular),
+            "c" => Ok(OutputFormat::Csv),
+            "j" => Ok(OutputFormat::Json),
+            "jv" => Ok(OutputFormat::JsonVerbose),
             _ => Err(ChumError::new("invalid operation requested")),
         }
     }
 }
 
+#[derive(Clone)]
 pub enum DataCap {
     LogicalData(u64),
     Percentage(u32),
+    Duration(time::Duration),
+    /*
+     * Operation count caps. A 'None' operation caps the total number of
+     * operations. The cap is reached once every listed count is reached.
+     */
+    Operations(Vec<(Option<Operation>, u64)>),
+    /* Every object in the queue has been deleted. */
+    Drained(Arc<ShardedQueue<ObjectRecord>>),
+}
+
+/* Settings for reporting that stay the same from one run to the next. */
+#[derive(Clone)]
+pub struct StatOptions {
+    pub interval: u64,
+    /* Results from the start of each run that are left out of its totals. */
+    pub warmup: time::Duration,
+    pub format: OutputFormat,
+    pub target: String,
+    pub protocol: String,
+    /* What tabular and CSV output call this part of the workload. */
+    pub stage: String,
+    /* Where a pupil sends each tick's stats, instead of printing them. */
+    pub report: Option<Sender<TickReport>>,
+}
+
+/* The stats for one tick, as a pupil reports them to its instructor. */
+pub struct TickReport {
+    pub warmup: bool,
+    pub elapsed: time::Duration, /* since the start of the run proper. */
+    pub ops: HashMap<Operation, WorkerStat>,
+}
+
+/* The stats for a whole run, returned once the run has ended. */
+pub struct RunStats {
+    pub start_time: SystemTime,
+    pub elapsed: time::Duration,
+    pub ops: HashMap<Operation, WorkerStat>,
+    pub interrupted: bool, /* the user stopped the run early */
+    pub incomplete: bool,  /* stats from some pupils never arrived */
 }
 
 /*
- * This thread reads results off of the channel. This tracks three sets of
+ * This reads results off of the channel. This tracks three sets of
  * stats:
  * - long term aggregate statistics
  * - per tick aggregate statistics
@@ -74,18 +135,33 @@ pub enum DataCap {
  * flag at the CLI.
  *
  * All stats are separated by operation (e.g. read, write, etc.).
+ *
+ * Results from the warmup period at the start of the run are printed with
+ * each tick but aren't added to the long term stats. Time and operation
+ * count caps start counting once the warmup is over.
+ *
+ * The run ends when a cap is reached or when something else (like a signal
+ * handler) sets the 'interrupted' flag. The workers are then told to stop
+ * through the 'stop' flag and their remaining results are drained from the
+ * channel. The totals for the whole run are returned.
+ *
+ * Operation count caps are enforced by the workers through 'budget', since
+ * they have to stop at an exact count. The worker that completes the last
+ * operation sets the 'stop' flag itself.
  */
 pub fn collect_stats(
-    rx: Receiver<Result<WorkerInfo, ChumError>>,
-    interval: u64,
-    format: OutputFormat,
-    data_cap: Option<DataCap>,
-    target: String,
-    protocol: String,
-) {
+    rx: Receiver<WorkerResult>,
+    opts: &StatOptions,
+    data_caps: &[DataCap],
+    budget: Option<&OpBudget>,
+    stop: &AtomicBool,
+    interrupted: &AtomicBool,
+) -> RunStats {
+    let format = opts.format;
     let mut total_bytes_written: u64 = 0;
     let mut op_agg = HashMap::new();
-    let start_time = SystemTime::now();
+    let mut start_time = SystemTime::now();
+    let mut warming = opts.warmup > time::Duration::from_secs(0);
 
     /*
      * This is copied code, and generally an abstraction leak. We should really
@@ -96,9 +172,25 @@ pub fn collect_stats(
      */
 
     loop {
-        thread::sleep(time::Duration::from_secs(interval));
+        /*
+         * Sleep for a full tick unless the warmup or a time cap expires before
+         * then. This way a time-capped run ends close to when the user asked
+         * it to, and no tick straddles the end of the warmup.
+         */
+        let mut tick = time::Duration::from_secs(opts.interval);
+        let elapsed = start_time.elapsed().unwrap_or_default();
+        if warming {
+            tick =
+                tick.min(opts.warmup.checked_sub(elapsed).unwrap_or_default());
+        } else {
+            for cap in data_caps.iter() {
+                if let DataCap::Duration(d) = cap {
+                    tick = tick.min(d.checked_sub(elapsed).unwrap_or_default());
+                }
+            }
+        }
+        sleep_unless_stopped(tick, &[interrupted, stop]);
 
-        let mut op_ticks = HashMap::new();
         let mut op_stats = HashMap::new();
 
         /*
@@ -106,102 +198,281 @@ pub fn collect_stats(
          * thread was sleeping.
          */
         for res in rx.try_iter() {
-            let wr: WorkerInfo;
-            match res {
-                Ok(wi) => wr = wi,
-                Err(e) => {
-                    if format == OutputFormat::HumanVerbose {
-                        println!("{}", e.to_string());
-                    }
-                    wr = WorkerInfo {
-                        id: thread::current().id(),
-                        op: Operation::Error,
-                        size: 0,
-                        ttfb: 0,
-                        rtt: 0,
-                    }
-                }
-            }
+            record_result(
+                res,
+                &format,
+                &mut total_bytes_written,
+                &mut op_stats,
+            );
+        }
 
-            if wr.op == Operation::Write {
-                total_bytes_written += wr.size;
+        /*
+         * The run ends when the user sends a signal or a cap is reached.
+         * Either way, tell the workers to stop and wait for them to report
+         * the operations they had in flight. The channel disconnects once
+         * every worker has exited.
+         */
+        let was_interrupted = interrupted.load(Ordering::SeqCst);
+        let done = was_interrupted
+            || stop.load(Ordering::SeqCst)
+            || cap_reached(
+                data_caps,
+                warming,
+                total_bytes_written,
+                start_time,
+                &opts.target,
+                &opts.protocol,
+            );
+        if done {
+            stop.store(true, Ordering::SeqCst);
+            for res in rx.iter() {
+                record_result(
+                    res,
+                    &format,
+                    &mut total_bytes_written,
+                    &mut op_stats,
+                );
             }
-
-            op_stats.entry(wr.op).or_insert_with(HashMap::new);
-
-            let thread_stats = op_stats.get_mut(&wr.op).unwrap();
-            thread_stats.entry(wr.id).or_insert_with(WorkerStat::new);
-            thread_stats.get_mut(&wr.id).unwrap().add_result(&wr);
-
-            op_ticks.entry(wr.op).or_insert_with(WorkerStat::new);
-            let tick_totals = op_ticks.get_mut(&wr.op).unwrap();
-            tick_totals.add_result(&wr);
-
-            op_agg.entry(wr.op).or_insert_with(WorkerStat::new);
-            let agg_totals = op_agg.get_mut(&wr.op).unwrap();
-            agg_totals.add_result(&wr);
         }
 
-        match format {
-            OutputFormat::Human | OutputFormat::HumanVerbose => print_human(
+        let op_ticks = merge_tick(&op_stats, &mut op_agg, warming);
+        match &opts.report {
+            /* The instructor prints the stats for every pupil together. */
+            Some(report) => {
+                let tick = TickReport {
+                    warmup: warming,
+                    elapsed: start_time.elapsed().unwrap_or_default(),
+                    ops: op_ticks,
+                };
+                /* The pupil stops the run if its instructor goes away. */
+                let _ = report.send(tick);
+            }
+            None => print_tick(
                 start_time,
                 &format,
+                &opts.stage,
+                warming,
                 op_stats,
                 op_ticks,
                 &mut op_agg,
             ),
-            OutputFormat::Tabular => print_tabular(
+        }
+
+        if done {
+            return RunStats {
                 start_time,
-                &format,
-                op_stats,
-                op_ticks,
-                &mut op_agg,
-            ),
+                elapsed: start_time.elapsed().unwrap_or_default(),
+                ops: op_agg,
+                interrupted: was_interrupted,
+                incomplete: false,
+            };
+        }
+
+        /* The run proper starts once the warmup is over. */
+        if warming && start_time.elapsed().unwrap_or_default() >= opts.warmup {
+            warming = false;
+            start_time = SystemTime::now();
+            if let Some(budget) = budget {
+                budget.arm();
+            }
+        }
+    }
+}
+
+/*
+ * Merge the stats from each thread (or pupil) into the stats for this tick,
+ * and those into the stats for the whole run unless it's still warming up.
+ * Returns the stats for the tick.
+ */
+pub fn merge_tick<K>(
+    op_stats: &HashMap<Operation, HashMap<K, WorkerStat>>,
+    op_agg: &mut HashMap<Operation, WorkerStat>,
+    warming: bool,
+) -> HashMap<Operation, WorkerStat> {
+    let mut op_ticks = HashMap::new();
+    for (op, thread_stats) in op_stats.iter() {
+        let tick_totals = op_ticks.entry(*op).or_insert_with(WorkerStat::new);
+        for stat in thread_stats.values() {
+            tick_totals.merge(stat);
         }
+    }
+    if !warming {
+        for (op, tick_totals) in op_ticks.iter() {
+            op_agg
+                .entry(*op)
+                .or_insert_with(WorkerStat::new)
+                .merge(tick_totals);
+        }
+    }
+    op_ticks
+}
+
+/*
+ * Print the stats for a tick in the user's format. 'op_stats' is keyed by
+ * thread, or by pupil for an instructor.
+ */
+pub fn print_tick<K: Debug>(
+    start_time: SystemTime,
+    format: &OutputFormat,
+    stage: &str,
+    warming: bool,
+    op_stats: HashMap<Operation, HashMap<K, WorkerStat>>,
+    op_ticks: HashMap<Operation, WorkerStat>,
+    op_agg: &mut HashMap<Operation, WorkerStat>,
+) {
+    match format {
+        OutputFormat::Human | OutputFormat::HumanVerbose => {
+            print_human(start_time, format, warming, op_stats, op_ticks, op_agg)
+        }
+        OutputFormat::Tabular | OutputFormat::Csv => {
+            print_tabular(format, stage, warming, op_ticks, op_agg)
+        }
+        OutputFormat::Json | OutputFormat::JsonVerbose => {
+            print_json(start_time, format, warming, op_stats, op_ticks, op_agg)
+        }
+    }
+}
+
+/*
+ * Sleep for the given duration, waking up early if any of the 'stop' flags
+ * is set.
+ */
+pub fn sleep_unless_stopped(dur: time::Duration, stop: &[&AtomicBool]) {
+    let deadline = Instant::now() + dur;
+    while !stop.iter().any(|s| s.load(Ordering::SeqCst)) {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        thread::sleep((deadline - now).min(STOP_POLL_INTERVAL));
+    }
+}
+
+/* Add a single worker result to the running counters and per-thread stats. */
+fn record_result(
+    res: WorkerResult,
+    format: &OutputFormat,
+    total_bytes_written: &mut u64,
+    op_stats: &mut HashMap<Operation, HashMap<ThreadId, WorkerStat>>,
+) {
+    let wr: WorkerInfo;
+    match res {
+        Ok(wi) => wr = wi,
+        Err(e) => {
+            if *format == OutputFormat::HumanVerbose {
+                println!("{}", e.err);
+            }
+
+            /* Count the error against the failed operation too. */
+            let kind = e.err.kind();
+            op_stats
+                .entry(e.op)
+                .or_default()
+                .entry(e.id)
+                .or_insert_with(WorkerStat::new)
+                .add_error(kind);
+
+            /* Data integrity failures aren't counted as generic errors. */
+            if kind != ErrorKind::Other {
+                return;
+            }
 
-        match data_cap {
-            Some(DataCap::LogicalData(cap)) => {
-                if total_bytes_written >= cap {
-                    /* Exit the thread, signalling and end of the program. */
-                    return;
+            wr = WorkerInfo {
+                id: e.id,
+                op: Operation::Error,
+                size: 0,
+                ttfb: time::Duration::from_secs(0),
+                rtt: time::Duration::from_secs(0),
+                age: None,
+            }
+        }
+    }
+
+    if wr.op == Operation::Write {
+        *total_bytes_written += wr.size;
+    }
+
+    op_stats.entry(wr.op).or_default();
+
+    let thread_stats = op_stats.get_mut(&wr.op).unwrap();
+    thread_stats.entry(wr.id).or_insert_with(WorkerStat::new);
+    thread_stats.get_mut(&wr.id).unwrap().add_result(&wr);
+}
+
+/*
+ * Returns true once any of the caps provided by the user has been reached.
+ * Time caps don't apply during the warmup. Operation count caps are left to
+ * the workers.
+ */
+fn cap_reached(
+    data_caps: &[DataCap],
+    warming: bool,
+    total_bytes_written: u64,
+    start_time: SystemTime,
+    target: &str,
+    protocol: &str,
+) -> bool {
+    for cap in data_caps {
+        match cap {
+            DataCap::LogicalData(cap) => {
+                if total_bytes_written >= *cap {
+                    return true;
                 }
             }
-            Some(DataCap::Percentage(cap)) => {
+            DataCap::Percentage(cap) => {
                 /* Percentage based accounting only supported by fs backend. */
                 if protocol != "fs" {
                     continue;
                 }
 
-                match fs3::statvfs(&target) {
+                match fs3::statvfs(target) {
                     Ok(stats) => {
                         let used =
                             stats.total_space() - stats.available_space();
                         let perc_used = (used * 100) / stats.total_space();
 
-                        if perc_used >= cap.into() {
-                            return;
+                        if perc_used >= u64::from(*cap) {
+                            return true;
                         }
                     }
                     Err(e) => {
-                        println!("statvfs error for {}: {}", &target, e);
-                        return;
+                        println!("statvfs error for {}: {}", target, e);
+                        return true;
                     }
                 }
             }
-            None => (),
+            DataCap::Duration(_) if warming => (),
+            DataCap::Duration(cap) => {
+                if start_time.elapsed().unwrap_or_default() >= *cap {
+                    return true;
+                }
+            }
+            DataCap::Operations(_) => (),
+            DataCap::Drained(queue) => {
+                if queue.is_empty() {
+                    return true;
+                }
+            }
         }
     }
+
+    false
 }
 
-fn print_human(
+fn print_human<K>(
     start_time: SystemTime,
     format: &OutputFormat,
-    mut op_stats: HashMap<Operation, HashMap<ThreadId, WorkerStat>>,
+    warming: bool,
+    mut op_stats: HashMap<Operation, HashMap<K, WorkerStat>>,
     mut op_ticks: HashMap<Operation, WorkerStat>,
     op_agg: &mut HashMap<Operation, WorkerStat>,
 ) {
     /* Print out the stats we gathered. */
-    println!("---");
+    if warming {
+        println!("--- warmup");
+    } else {
+        println!("---");
+    }
     if *format == OutputFormat::HumanVerbose {
         let mut i = 0;
         for (op, op_map) in op_stats.iter_mut() {
@@ -239,6 +510,10 @@ fn print_human(
             println!("\t{}", worker.serialize_relative());
         }
     }
+    let integrity = integrity_totals(&op_ticks);
+    if integrity.integrity_errors() > 0 {
+        println!("Tick (verify)\t{}", integrity.serialize_integrity());
+    }
 
     for (op, worker) in op_agg.iter_mut() {
         print!("Total ({})", op);
@@ -253,80 +528,295 @@ fn print_human(
             println!("\t{}", worker.serialize_absolute(elapsed_sec));
         }
     }
+    let integrity = integrity_totals(op_agg);
+    if integrity.integrity_errors() > 0 {
+        println!("Total (verify)\t{}", integrity.serialize_integrity());
+    }
 }
 
-fn print_tabular(
-    _: SystemTime,
-    _: &OutputFormat,
-    _: HashMap<Operation, HashMap<ThreadId, WorkerStat>>,
+/* Sum the data integrity failures of every operation. */
+fn integrity_totals(stats: &HashMap<Operation, WorkerStat>) -> WorkerStat {
+    let mut total = WorkerStat::new();
+    for stat in stats.values() {
+        total.corrupt += stat.corrupt;
+        total.truncated += stat.truncated;
+        total.misdirected += stat.misdirected;
+    }
+    total
+}
+
+/*
+ * Print the totals for the whole run once it has ended. Tabular and CSV output
+ * already carry running totals on every line, so there is nothing more to
+ * print.
+ */
+pub fn print_summary(format: &OutputFormat, stats: &mut RunStats) {
+    let start_time = stats.start_time;
+    let op_agg = &mut stats.ops;
+    match format {
+        OutputFormat::Tabular | OutputFormat::Csv => return,
+        OutputFormat::Json | OutputFormat::JsonVerbose => {
+            println!(
+                "{}",
+                json!({
+                    "type": "summary",
+                    "time": unix_time(),
+                    "elapsed": elapsed_secs(start_time),
+                    "incomplete": stats.incomplete,
+                    "total": ops_to_json(op_agg, Some(start_time)),
+                })
+            );
+            return;
+        }
+        _ => (),
+    }
+
+    let elapsed_sec = start_time.elapsed().unwrap().as_secs().max(1);
+    println!("===");
+    if stats.incomplete {
+        println!("Summary ({}s, incomplete)", elapsed_sec);
+    } else {
+        println!("Summary ({}s)", elapsed_sec);
+    }
+    for (op, worker) in op_agg.iter_mut() {
+        print!("Total ({})", op);
+        if op == &Operation::Error {
+            println!("\t{} errors", worker.objs);
+        } else {
+            println!("\t{}", worker.serialize_absolute(elapsed_sec));
+        }
+    }
+    let integrity = integrity_totals(op_agg);
+    if integrity.integrity_errors() > 0 {
+        println!("Total (verify)\t{}", integrity.serialize_integrity());
+    }
+}
+
+pub fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+fn elapsed_secs(start_time: SystemTime) -> f64 {
+    start_time.elapsed().unwrap_or_default().as_secs_f64()
+}
+
+/*
+ * Serialize stats for every operation, even those that saw no activity, so
+ * consumers always find the same fields. Errors only carry a count. When a
+ * start time is given the average throughput since then is included too.
+ */
+pub fn ops_to_json(
+    stats: &HashMap<Operation, WorkerStat>,
+    start_time: Option<SystemTime>,
+) -> serde_json::Value {
+    let zero_stat = WorkerStat::new();
+    let mut map = serde_json::Map::new();
+
+    for op in &[Operation::Read, Operation::Write, Operation::Delete] {
+        let stat = stats.get(op).unwrap_or(&zero_stat);
+        let mut val = stat.to_json();
+        if let Some(start) = start_time {
+            let elapsed = elapsed_secs(start);
+            if elapsed > 0.0 {
+                val["objects_per_sec"] = json!(stat.objs as f64 / elapsed);
+                val["bytes_per_sec"] = json!(stat.data as f64 / elapsed);
+            }
+        }
+        map.insert(op.to_string(), val);
+    }
+
+    let errors = stats.get(&Operation::Error).unwrap_or(&zero_stat).objs;
+    map.insert(Operation::Error.to_string(), json!({ "objects": errors }));
+
+    serde_json::Value::Object(map)
+}
+
+/*
+ * One JSON object per tick. Per-thread stats are included in verbose mode,
+ * keyed by operation and then thread.
+ */
+fn print_json<K: Debug>(
+    start_time: SystemTime,
+    format: &OutputFormat,
+    warmup: bool,
+    op_stats: HashMap<Operation, HashMap<K, WorkerStat>>,
     op_ticks: HashMap<Operation, WorkerStat>,
     op_agg: &mut HashMap<Operation, WorkerStat>,
 ) {
-    let zero_stat = WorkerStat::new();
+    let mut obj = json!({
+        "type": "tick",
+        "time": unix_time(),
+        "elapsed": elapsed_secs(start_time),
+        "warmup": warmup,
+        "tick": ops_to_json(&op_ticks, None),
+        "total": ops_to_json(op_agg, Some(start_time)),
+    });
+
+    if *format == OutputFormat::JsonVerbose {
+        let mut threads = serde_json::Map::new();
+        for (op, op_map) in op_stats.iter() {
+            let mut op_threads = serde_json::Map::new();
+            for (id, worker) in op_map.iter() {
+                op_threads.insert(format!("{:?}", id), worker.to_json());
+            }
+            threads
+                .insert(op.to_string(), serde_json::Value::Object(op_threads));
+        }
+        obj["threads"] = serde_json::Value::Object(threads);
+    }
+
+    println!("{}", obj);
+}
+
+/*
+ * Tabular and CSV output share a schema. Every line has the time, the stage of
+ * the workload and whether it's still warming up, then a group of columns for
+ * each operation's stats this tick, then the running totals, then the data
+ * integrity failures for the tick and in total. Bump the version whenever the
+ * columns change.
+ */
+const TABULAR_SCHEMA: u32 = 5;
+const TABULAR_INTEGRITY: [&str; 3] = ["corrupt", "truncated", "misdirected"];
+const TABULAR_OPS: [Operation; 3] =
+    [Operation::Read, Operation::Write, Operation::Delete];
+
+fn tabular_delimiter(format: &OutputFormat) -> &'static str {
+    match format {
+        OutputFormat::Csv => ",",
+        _ => " ",
+    }
+}
 
-    let time = match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(time) => format!("{}", time.as_secs()),
-        Err(_) => String::from("0"),
+/*
+ * Print the schema version (as a comment) and the column names. This is
+ * optional so that existing consumers of headerless output keep working.
+ */
+pub fn print_tabular_header(format: &OutputFormat) {
+    let mut cols = vec![
+        "time".to_string(),
+        "stage".to_string(),
+        "warmup".to_string(),
+    ];
+    for op in TABULAR_OPS.iter() {
+        cols.push(format!("{}_count", op));
+        cols.push(format!("{}_bytes", op));
+        cols.push(format!("{}_ttfb_avg", op));
+        cols.push(format!("{}_rtt_avg", op));
+        for (name, _) in PERCENTILES.iter() {
+            cols.push(format!("{}_rtt_{}", op, name));
+        }
+        cols.push(format!("{}_rtt_max", op));
+        cols.push(format!("{}_errors", op));
+    }
+    for op in TABULAR_OPS.iter() {
+        cols.push(format!("total_{}_count", op));
+        cols.push(format!("total_{}_bytes", op));
+    }
+    cols.push("total_errors".to_string());
+    for name in TABULAR_INTEGRITY.iter() {
+        cols.push(name.to_string());
+    }
+    for name in TABULAR_INTEGRITY.iter() {
+        cols.push(format!("total_{}", name));
+    }
+
+    let name = match format {
+        OutputFormat::Csv => "csv",
+        _ => "tabular",
     };
+    println!("# chum {} schema {}", name, TABULAR_SCHEMA);
+    println!("{}", cols.join(tabular_delimiter(format)));
+}
+
+fn print_tabular(
+    format: &OutputFormat,
+    stage: &str,
+    warming: bool,
+    op_ticks: HashMap<Operation, WorkerStat>,
+    op_agg: &mut HashMap<Operation, WorkerStat>,
+) {
+    let zero_stat = WorkerStat::new();
+    let mut cols = vec![
+        unix_time().to_string(),
+        stage.to_string(),
+        u8::from(warming).to_string(),
+    ];
 
     /*
-     * Per-tick (-i interval flag) stats.
+     * Per-tick (-i interval flag) stats. Latencies are in milliseconds.
      */
-    let reader_stats = match op_ticks.get(&Operation::Read) {
-        Some(stats) => stats,
-        None => &zero_stat,
-    };
-
-    let writer_stats = match op_ticks.get(&Operation::Write) {
-        Some(stats) => stats,
-        None => &zero_stat,
-    };
+    for op in TABULAR_OPS.iter() {
+        let stats = op_ticks.get(op).unwrap_or(&zero_stat);
+        cols.push(stats.objs.to_string());
+        cols.push(stats.data.to_string());
+        cols.push(format!("{:.3}", stats.avg_ttfb()));
+        cols.push(format!("{:.3}", stats.avg_rtt()));
+        for val in WorkerStat::percentiles(&stats.rtt_hist) {
+            cols.push(format!("{:.3}", micros_to_ms(val)));
+        }
+        cols.push(stats.errors.to_string());
+    }
 
-    let error_stats = match op_ticks.get(&Operation::Error) {
-        Some(stats) => stats,
-        None => &zero_stat,
-    };
+    /*
+     * Totals since start.
+     */
+    for op in TABULAR_OPS.iter() {
+        let stats = op_agg.get(op).unwrap_or(&zero_stat);
+        cols.push(stats.objs.to_string());
+        cols.push(stats.data.to_string());
+    }
+    let errors = op_agg.get(&Operation::Error).unwrap_or(&zero_stat);
+    cols.push(errors.objs.to_string());
 
     /*
-     * Total bytes read and written since start.
+     * Data integrity failures, this tick and since start. These are in the
+     * same order as TABULAR_INTEGRITY.
      */
-    let agg_read = match op_agg.get(&Operation::Read) {
-        Some(stats) => stats,
-        None => &zero_stat,
-    };
+    for stats in &[integrity_totals(&op_ticks), integrity_totals(op_agg)] {
+        cols.push(stats.corrupt.to_string());
+        cols.push(stats.truncated.to_string());
+        cols.push(stats.misdirected.to_string());
+    }
 
-    let agg_write = match op_agg.get(&Operation::Write) {
-        Some(stats) => stats,
-        None => &zero_stat,
-    };
+    println!("{}", cols.join(tabular_delimiter(format)));
+}
 
-    println!(
-        "{} {} {} {} {} {} {} {} {} {} {} {}",
-        time,
-        reader_stats.objs,
-        writer_stats.objs,
-        reader_stats.data,
-        writer_stats.data,
-        reader_stats.ttfb,
-        writer_stats.ttfb,
-        reader_stats.rtt,
-        writer_stats.rtt,
-        error_stats.objs,
-        agg_read.data,
-        agg_write.data,
-    );
+/*
+ * Most errors are just a message. Errors found while verifying the data read
+ * back from the target are tallied separately from other errors, so they carry
+ * a kind.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Other,
+    Corrupt,     /* the data doesn't match what was written. */
+    Truncated,   /* the data is shorter than what was written. */
+    Misdirected, /* the data belongs to a different object. */
 }
 
 #[derive(Debug, PartialEq)]
 pub struct ChumError {
+    kind: ErrorKind,
     msg: String,
 }
 impl ChumError {
     pub fn new(msg: &str) -> Self {
+        ChumError::with_kind(ErrorKind::Other, msg)
+    }
+
+    pub fn with_kind(kind: ErrorKind, msg: &str) -> Self {
         ChumError {
+            kind,
             msg: msg.to_string(),
         }
     }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
 }
 impl Error for ChumError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
@@ -380,6 +870,115 @@ pub fn parse_human(val: &str) -> Result<u64, ChumError> {
     }
 }
 
+/*
+ * Convert a human-readable duration (e.g. '30m') to a Duration. A bare number
+ * is interpreted as seconds.
+ */
+pub fn parse_duration(val: &str) -> Result<time::Duration, ChumError> {
+    let dur_re = Regex::new(r"^(\d+)([smhdSMHD]?)$").unwrap();
+    let caps = match dur_re.captures(val) {
+        Some(caps) => caps,
+        None => {
+            return Err(ChumError::new(
+                "provided duration must be a positive number with an \
+                 optional unit suffix (s, m, h, d)",
+            ))
+        }
+    };
+
+    let num = caps[1]
+        .parse::<u64>()
+        .map_err(|err| ChumError::new(&err.to_string()))?;
+    if num == 0 {
+        return Err(ChumError::new("provided duration must be non-zero"));
+    }
+
+    let secs = match caps[2].to_ascii_lowercase().as_ref() {
+        "" | "s" => num,
+        "m" => num * 60,
+        "h" => num * 60 * 60,
+        "d" => num * 60 * 60 * 24,
+        _ => return Err(ChumError::new("unrecognized unit suffix")),
+    };
+
+    Ok(time::Duration::from_secs(secs))
+}
+
+/*
+ * Parse an operation count cap like:
+ *   1000000
+ * or
+ *   r:500000,w:100000
+ *
+ * A bare number caps the total number of operations. A number prefixed with
+ * an operation caps the number of operations of that type.
+ */
+pub fn parse_operation_caps(
+    val: &str,
+) -> Result<Vec<(Option<Operation>, u64)>, ChumError> {
+    let mut caps = Vec::new();
+
+    for s in val.split(',') {
+        let tok: Vec<&str> = s.split(':').collect();
+        let (op, count) = match tok.len() {
+            1 => (None, tok[0]),
+            2 => {
+                let op: Operation = tok[0].parse()?;
+                if op == Operation::Error {
+                    return Err(ChumError::new(
+                        "errors can't be used as an operation cap",
+                    ));
+                }
+                (Some(op), tok[1])
+            }
+            _ => {
+                return Err(ChumError::new(&format!(
+                    "too many multiples in token '{}'",
+                    tok.join(":")
+                )))
+            }
+        };
+
+        let count = count.parse::<u64>().map_err(|_| {
+            ChumError::new(&format!("failed to parse '{}' as a number", count))
+        })?;
+        if count == 0 {
+            return Err(ChumError::new("operation caps must be non-zero"));
+        }
+        caps.push((op, count));
+    }
+
+    Ok(caps)
+}
+
+/*
+ * Make sure that every operation with a count cap is part of the workload.
+ * Otherwise the run would never end.
+ */
+pub fn check_operation_caps(
+    caps: &[DataCap],
+    ops: &[Operation],
+) -> Result<(), ChumError> {
+    for cap in caps {
+        if let DataCap::Operations(c) = cap {
+            for (op, _) in c {
+                match op {
+                    Some(op) if !ops.contains(op) => {
+                        return Err(ChumError::new(&format!(
+                            "can't cap {} operations, which aren't part of \
+                             the workload",
+                            op
+                        )))
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
 /*
  * Expand an input string like:
  *   1,2,3
@@ -459,46 +1058,127 @@ pub fn convert_operation_distribution(
  * The user provided the path to a file. This file contains a listing of objects
  * in the 'chum' namespace that chum should read back.
  *
- * This function pulls each of these file names from the listing file and
+ * This function pulls each of these objects from the listing file and
  * inserts them into the chum read queue. The read worker will then pull them
  * off the queue as it normally would (using whatever algorithm the user
- * specified).
+ * specified). Each line is either a bare file name or a full ObjectRecord, as
+ * written by '--write-list', or a tombstone for an object since deleted.
  *
  * The default errors we get from the OS and the uuid crate are pretty plain, so
  * we wrap them in a more helpful ChumError.
  */
-pub fn populate_queue(
-    queue: Arc<Mutex<Queue<String>>>,
+pub fn populate_queue<R: Rng>(
+    queue: &ShardedQueue<ObjectRecord>,
     readlist: String,
+    rng: &mut R,
 ) -> Result<(), ChumError> {
+    /*
+     * Objects deleted by the run that wrote the listing are left out. Their
+     * tombstones come after them, so the listing is read twice: once for the
+     * names of the deleted objects, then for the objects. Only tombstones have
+     * to be parsed the first time around.
+     */
+    let mut deleted = HashSet::new();
+    for line in read_listing(&readlist)? {
+        let line = line?;
+        if !line.contains("\"deleted\"") {
+            continue;
+        }
+        if let ListEntry::Deleted(rec) = line.parse()? {
+            deleted.insert(rec.name());
+        }
+    }
+
+    let mut last_dir = None;
+    for line in read_listing(&readlist)? {
+        let mut rec = match line?.parse()? {
+            ListEntry::Written(rec) => rec,
+            ListEntry::Deleted(_) => continue,
+        };
+        if !deleted.is_empty() && deleted.contains(&rec.name()) {
+            continue;
+        }
+        rec.share_dir(&mut last_dir);
+        queue.insert(rec, rng);
+    }
+
+    Ok(())
+}
+
+/* The lines of a listing file. */
+fn read_listing(
+    readlist: &str,
+) -> Result<impl Iterator<Item = Result<String, ChumError>>, ChumError> {
     let file = File::open(readlist).map_err(|e| {
         ChumError::new(&format!(
             "failed to open read listing file: {}",
             e.to_string()
         ))
     })?;
-    let br = BufReader::new(file);
-
-    let mut q = queue.lock().unwrap();
-    for pathstr in br.lines() {
-        let pathstr: String = match pathstr {
-            Ok(x) => x,
-            Err(_) => {
-                return Err(ChumError::new(
-                    "failed to read line from read listing file",
-                ))
-            }
+
+    Ok(BufReader::new(file).lines().map(|line| {
+        line.map_err(|_| {
+            ChumError::new("failed to read line from read listing file")
+        })
+    }))
+}
+
+/*
+ * The user provided the path to a file that should list every object chum
+ * writes, in the format that populate_queue reads. A later run can then read
+ * the same objects back with '--read-list'.
+ *
+ * Workers send the record of each object they write, and a tombstone for each
+ * one they delete, through the returned channel. The returned thread appends
+ * them to the file along with the protocol. The file is flushed
+ * whenever the thread catches up with the workers, so little is lost if chum
+ * dies part way through a run. The thread exits once every sender is dropped.
+ */
+pub fn start_write_list(
+    writelist: String,
+    protocol: String,
+) -> Result<(Sender<ListEntry>, JoinHandle<()>), ChumError> {
+    let file = File::create(writelist).map_err(|e| {
+        ChumError::new(&format!(
+            "failed to create write listing file: {}",
+            e.to_string()
+        ))
+    })?;
+    let mut bw = BufWriter::new(file);
+
+    let (tx, rx): (Sender<ListEntry>, Receiver<ListEntry>) = channel();
+    let jh = thread::spawn(move || {
+        let line = |entry: ListEntry| {
+            let mut obj = entry.to_json();
+            obj["backend"] = json!(protocol);
+            obj
         };
+        while let Ok(rec) = rx.recv() {
+            let res = writeln!(bw, "{}", line(rec))
+                .and_then(|_| {
+                    rx.try_iter()
+                        .try_for_each(|rec| writeln!(bw, "{}", line(rec)))
+                })
+                .and_then(|_| bw.flush());
 
-        q.insert(pathstr);
-    }
+            /*
+             * Stop listening, rather than quietly dropping names. The workers
+             * carry on without the list.
+             */
+            if let Err(e) = res {
+                eprintln!("failed to write to write listing file: {}", e);
+                return;
+            }
+        }
+    });
 
-    Ok(())
+    Ok((tx, jh))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::queue::QueueMode;
 
     #[test]
     fn test_parse_human() -> Result<(), ChumError> {
@@ -553,6 +1233,93 @@ mod tests {
         let _ = parse_human("10000000000T");
     }
 
+    #[test]
+    fn test_parse_duration() -> Result<(), ChumError> {
+        assert_eq!(parse_duration("45")?, time::Duration::from_secs(45));
+        assert_eq!(parse_duration("90s")?, time::Duration::from_secs(90));
+        assert_eq!(parse_duration("30m")?, time::Duration::from_secs(1800));
+        assert_eq!(parse_duration("2H")?, time::Duration::from_secs(7200));
+        assert_eq!(parse_duration("1d")?, time::Duration::from_secs(86400));
+
+        assert_eq!(
+            parse_duration("0m"),
+            Err(ChumError::new("provided duration must be non-zero"))
+        );
+        for bad in &["", "m", "1.5h", "-1s", "10w", "1h30m"] {
+            assert_eq!(
+                parse_duration(bad),
+                Err(ChumError::new(
+                    "provided duration must be a positive number with an \
+                     optional unit suffix (s, m, h, d)"
+                ))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_operation_caps() -> Result<(), ChumError> {
+        assert_eq!(parse_operation_caps("1000")?, vec![(None, 1000)]);
+        assert_eq!(
+            parse_operation_caps("r:500,w:100")?,
+            vec![(Some(Operation::Read), 500), (Some(Operation::Write), 100)]
+        );
+        assert_eq!(
+            parse_operation_caps("10,d:5")?,
+            vec![(None, 10), (Some(Operation::Delete), 5)]
+        );
+
+        assert_eq!(
+            parse_operation_caps("x:10"),
+            Err(ChumError::new("invalid operation requested"))
+        );
+        assert_eq!(
+            parse_operation_caps("e:10"),
+            Err(ChumError::new("errors can't be used as an operation cap"))
+        );
+        assert_eq!(
+            parse_operation_caps("r:1k"),
+            Err(ChumError::new("failed to parse '1k' as a number"))
+        );
+        assert_eq!(
+            parse_operation_caps("w:0"),
+            Err(ChumError::new("operation caps must be non-zero"))
+        );
+        assert_eq!(
+            parse_operation_caps("r:1:2"),
+            Err(ChumError::new("too many multiples in token 'r:1:2'"))
+        );
+
+        let caps = [DataCap::Operations(parse_operation_caps("10,w:5")?)];
+        assert_eq!(check_operation_caps(&caps, &[Operation::Write]), Ok(()));
+        assert_eq!(
+            check_operation_caps(&caps, &[Operation::Read]),
+            Err(ChumError::new(
+                "can't cap write operations, which aren't part of the workload"
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cap_reached_warmup() {
+        let start = SystemTime::now() - time::Duration::from_secs(5);
+        let reached = |caps: &[DataCap], warming| {
+            cap_reached(caps, warming, 100, start, "", "webdav")
+        };
+
+        /* Time caps wait for the warmup to end. */
+        let caps = [DataCap::Duration(time::Duration::from_secs(1))];
+        assert!(!reached(&caps, true));
+        assert!(reached(&caps, false));
+
+        /* Data caps protect the target, so they always apply. */
+        assert!(reached(&[DataCap::LogicalData(50)], true));
+        assert!(!reached(&[DataCap::LogicalData(500)], false));
+    }
+
     #[test]
     fn test_expand_distribution() -> Result<(), ChumError> {
         assert_eq!(expand_distribution("1,2,3")?, vec!["1", "2", "3"]);
@@ -599,4 +1366,55 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_write_list_roundtrip() -> Result<(), ChumError> {
+        let path = std::env::temp_dir()
+            .join(format!("chum-write-list-test-{}", std::process::id()));
+        let dir: Arc<str> = "/chum/2020/101/0/".into();
+        let ids = [uuid::Uuid::new_v4(), uuid::Uuid::new_v4()];
+
+        let (tx, jh) = start_write_list(
+            path.to_string_lossy().to_string(),
+            "fs".to_string(),
+        )?;
+        let recs: Vec<ObjectRecord> = ids
+            .iter()
+            .map(|id| ObjectRecord::new(Some(dir.clone()), *id, 4096))
+            .collect();
+        for rec in recs.iter() {
+            tx.send(ListEntry::Written(rec.clone())).unwrap();
+        }
+        /* Deleted objects aren't read back. */
+        tx.send(ListEntry::Deleted(recs[1].clone())).unwrap();
+        drop(tx);
+        jh.join().unwrap();
+
+        let listing = std::fs::read_to_string(&path)?;
+        assert!(listing.lines().all(|l| l.contains("\"backend\":\"fs\"")));
+
+        /* Bare names can be mixed in with full records. */
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+        writeln!(file, "{}notes.txt", dir)?;
+
+        /* The listing reads back in as the objects that were written. */
+        let queue = ShardedQueue::new(QueueMode::Lru, 1);
+        let mut rng = rand::thread_rng();
+        populate_queue(&queue, path.to_string_lossy().to_string(), &mut rng)?;
+
+        let mut read = Vec::new();
+        while let Some(rec) = queue.remove(&mut rng) {
+            read.push((rec.name(), rec.size()));
+        }
+        assert_eq!(
+            read,
+            vec![
+                (format!("{}{}", dir, ids[0]), Some(4096)),
+                (format!("{}notes.txt", dir), None),
+            ]
+        );
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,332 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::utils::{ChumError, ErrorKind};
+
+/*
+ * Data integrity verification.
+ *
+ * When verification is enabled every object that chum writes starts with a
+ * header identifying the object. The rest of the object is generated from the
+ * object's name and a seed. Reading the object back regenerates the expected
+ * bytes and compares them with what the target returned.
+ *
+ * Header layout:
+ *   magic (8 bytes) | object size, little-endian (8) | object name (48)
+ *
+ * The name is zero padded. Objects smaller than the header carry a truncated
+ * header.
+ */
+const MAGIC: &[u8; 8] = b"CHUMVRF1";
+const HEADER_LEN: usize = 64;
+const NAME_OFFSET: usize = 16;
+
+/* Seed used when the user doesn't provide one. */
+pub const DEF_SEED: u64 = 0;
+
+fn header(name: &str, size: u64) -> [u8; HEADER_LEN] {
+    let mut hdr = [0u8; HEADER_LEN];
+    hdr[0..8].copy_from_slice(MAGIC);
+    hdr[8..NAME_OFFSET].copy_from_slice(&size.to_le_bytes());
+
+    let name = name.as_bytes();
+    let len = name.len().min(HEADER_LEN - NAME_OFFSET);
+    hdr[NAME_OFFSET..NAME_OFFSET + len].copy_from_slice(&name[..len]);
+    hdr
+}
+
+/*
+ * A small xorshift64* generator for the object body. It is seeded with an
+ * FNV-1a hash of the object name and the user's seed, so the body only
+ * depends on those two things.
+ */
+struct Generator {
+    state: u64,
+    word: [u8; 8],
+    used: usize,
+}
+
+impl Generator {
+    fn new(name: &str, seed: u64) -> Generator {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for b in name.as_bytes().iter().chain(seed.to_le_bytes().iter()) {
+            hash ^= u64::from(*b);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+
+        Generator {
+            /* xorshift gets stuck at zero. */
+            state: if hash == 0 { 1 } else { hash },
+            word: [0; 8],
+            used: 8,
+        }
+    }
+
+    fn next_word(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for b in buf.iter_mut() {
+            if self.used == self.word.len() {
+                self.word = self.next_word().to_le_bytes();
+                self.used = 0;
+            }
+            *b = self.word[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+/*
+ * The contents of an object, produced a piece at a time so that streaming
+ * uploads don't need the whole object in memory.
+ */
+pub struct Payload {
+    header: [u8; HEADER_LEN],
+    gen: Generator,
+    size: u64,
+    offset: u64,
+}
+
+impl Payload {
+    pub fn new(name: &str, size: u64, seed: u64) -> Payload {
+        Payload {
+            header: header(name, size),
+            gen: Generator::new(name, seed),
+            size,
+            offset: 0,
+        }
+    }
+
+    /*
+     * Fill 'buf' with the next bytes of the object. Returns the number of
+     * bytes written, which is zero once the whole object has been produced.
+     */
+    pub fn fill(&mut self, buf: &mut [u8]) -> usize {
+        let remaining = self.size - self.offset;
+        let len = (buf.len() as u64).min(remaining) as usize;
+        let buf = &mut buf[..len];
+
+        let mut done = 0;
+        if self.offset < HEADER_LEN as u64 {
+            let start = self.offset as usize;
+            let n = (HEADER_LEN - start).min(len);
+            buf[..n].copy_from_slice(&self.header[start..start + n]);
+            done = n;
+        }
+        self.gen.fill(&mut buf[done..]);
+
+        self.offset += len as u64;
+        len
+    }
+
+    /* The whole object in one buffer. */
+    pub fn into_vec(mut self) -> Vec<u8> {
+        let mut buf = vec![0u8; self.size as usize];
+        self.fill(&mut buf);
+        buf
+    }
+}
+
+/*
+ * Checks the contents of an object as it is read. Feed it every chunk of the
+ * object in order, then call finish() to learn whether the object was intact.
+ */
+pub struct Verifier {
+    name: String,
+    head: Vec<u8>,
+    gen: Generator,
+    scratch: Vec<u8>,
+    len: u64,
+    body_ok: bool,
+}
+
+impl Verifier {
+    pub fn new(name: &str, seed: u64) -> Verifier {
+        Verifier {
+            name: name.to_string(),
+            head: Vec::with_capacity(HEADER_LEN),
+            gen: Generator::new(name, seed),
+            scratch: Vec::new(),
+            len: 0,
+            body_ok: true,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.len += data.len() as u64;
+
+        let mut data = data;
+        if self.head.len() < HEADER_LEN {
+            let n = (HEADER_LEN - self.head.len()).min(data.len());
+            self.head.extend_from_slice(&data[..n]);
+            data = &data[n..];
+        }
+
+        /* Once the body is known to be bad there's no need to keep checking. */
+        if data.is_empty() || !self.body_ok {
+            return;
+        }
+        self.scratch.resize(data.len(), 0);
+        self.gen.fill(&mut self.scratch);
+        if self.scratch[..] != data[..] {
+            self.body_ok = false;
+        }
+    }
+
+    pub fn finish(self) -> Result<(), ChumError> {
+        let fail = |kind, what: &str| {
+            Err(ChumError::with_kind(
+                kind,
+                &format!("verifying {} failed: {}", self.name, what),
+            ))
+        };
+
+        /* Too short to hold the size, so compare against a header this long. */
+        if self.head.len() < NAME_OFFSET {
+            let expected = header(&self.name, self.len);
+            if self.head[..] != expected[..self.head.len()] {
+                return fail(ErrorKind::Corrupt, "header mismatch");
+            }
+            return Ok(());
+        }
+
+        if self.head[0..8] != MAGIC[..] {
+            return fail(ErrorKind::Corrupt, "missing header");
+        }
+
+        let name_field = &self.head[NAME_OFFSET..];
+        let expected = header(&self.name, 0);
+        if name_field[..]
+            != expected[NAME_OFFSET..NAME_OFFSET + name_field.len()]
+        {
+            let found: Vec<u8> = name_field
+                .iter()
+                .take_while(|b| **b != 0)
+                .cloned()
+                .collect();
+            return fail(
+                ErrorKind::Misdirected,
+                &format!("found object {}", String::from_utf8_lossy(&found)),
+            );
+        }
+
+        let mut size = [0u8; 8];
+        size.copy_from_slice(&self.head[8..NAME_OFFSET]);
+        let size = u64::from_le_bytes(size);
+        if self.len < size {
+            return fail(
+                ErrorKind::Truncated,
+                &format!("read {} of {} bytes", self.len, size),
+            );
+        }
+        if self.len > size {
+            return fail(
+                ErrorKind::Corrupt,
+                &format!("read {} bytes, expected {}", self.len, size),
+            );
+        }
+
+        if !self.body_ok {
+            return fail(ErrorKind::Corrupt, "content mismatch");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME: &str = "0f1e2d3c-4b5a-6978-8796-a5b4c3d2e1f0";
+
+    fn verify(name: &str, data: &[u8], chunk: usize) -> Result<(), ChumError> {
+        let mut v = Verifier::new(name, DEF_SEED);
+        for c in data.chunks(chunk) {
+            v.update(c);
+        }
+        v.finish()
+    }
+
+    fn kind(res: Result<(), ChumError>) -> ErrorKind {
+        res.expect_err("verification should have failed").kind()
+    }
+
+    #[test]
+    fn test_verify_roundtrip() {
+        for size in &[0, 1, 15, 16, 40, 64, 65, 4096, 100_000] {
+            let data = Payload::new(NAME, *size, DEF_SEED).into_vec();
+            assert_eq!(data.len() as u64, *size);
+            for chunk in &[1, 7, 64, 65536] {
+                assert!(verify(NAME, &data, *chunk).is_ok(), "size {}", size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_streaming_payload() {
+        /* Filling in odd sized pieces gives the same bytes. */
+        let whole = Payload::new(NAME, 10_000, DEF_SEED).into_vec();
+        let mut p = Payload::new(NAME, 10_000, DEF_SEED);
+        let mut pieces = Vec::new();
+        let mut buf = [0u8; 333];
+        loop {
+            let n = p.fill(&mut buf);
+            if n == 0 {
+                break;
+            }
+            pieces.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(whole, pieces);
+
+        /* A different seed gives a different body. */
+        let other = Payload::new(NAME, 10_000, DEF_SEED + 1).into_vec();
+        assert_eq!(whole[..HEADER_LEN], other[..HEADER_LEN]);
+        assert_ne!(whole[HEADER_LEN..], other[HEADER_LEN..]);
+    }
+
+    #[test]
+    fn test_verify_failures() {
+        let data = Payload::new(NAME, 4096, DEF_SEED).into_vec();
+
+        let mut flipped = data.clone();
+        flipped[2000] ^= 1;
+        assert_eq!(kind(verify(NAME, &flipped, 512)), ErrorKind::Corrupt);
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(kind(verify(NAME, &bad_magic, 512)), ErrorKind::Corrupt);
+
+        assert_eq!(
+            kind(verify(NAME, &data[..1000], 512)),
+            ErrorKind::Truncated
+        );
+
+        let mut longer = data.clone();
+        longer.push(0);
+        assert_eq!(kind(verify(NAME, &longer, 512)), ErrorKind::Corrupt);
+
+        let other =
+            Payload::new("some-other-object", 4096, DEF_SEED).into_vec();
+        let err = verify(NAME, &other, 512).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Misdirected);
+        assert!(err.to_string().contains("some-other-object"));
+
+        let tiny = Payload::new(NAME, 10, DEF_SEED).into_vec();
+        let mut tiny_flipped = tiny.clone();
+        tiny_flipped[9] ^= 1;
+        assert_eq!(kind(verify(NAME, &tiny_flipped, 3)), ErrorKind::Corrupt);
+    }
+}
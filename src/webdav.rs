@@ -6,14 +6,19 @@
  * Copyright 2020 Joyent, Inc.
  */
 
+use crate::cleanup::{Cleaner, BATCH_SIZE};
+use crate::record::ObjectRecord;
 use crate::utils::ChumError;
-use crate::worker::{Backend, Operation, WorkerInfo, WorkerOptions};
+use crate::verify::{Payload, Verifier};
+use crate::worker::{
+    random_uuid, Backend, Operation, WorkerInfo, WorkerOptions,
+};
 
-use curl::easy::{Easy, HttpVersion};
+use curl::easy::{Easy, HttpVersion, List};
+use regex::Regex;
 use uuid::Uuid;
 
-use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::rngs::StdRng;
 use rand::AsByteSliceMut;
 use rand::Rng;
 
@@ -24,12 +29,11 @@ pub struct WebDav {
     buf: Vec<u8>,
     client: Easy,
     wopts: WorkerOptions,
+    rng: StdRng,
 }
 
 impl WebDav {
-    pub fn new(wopts: WorkerOptions) -> WebDav {
-        let mut rng = thread_rng();
-
+    pub fn new(wopts: WorkerOptions, mut rng: StdRng) -> WebDav {
         /*
          * Create a random buffer. This is the data that will be sent
          * to the target server.
@@ -49,6 +53,7 @@ impl WebDav {
             buf: vec,
             client,
             wopts,
+            rng,
         }
     }
 }
@@ -56,19 +61,13 @@ impl WebDav {
 impl Backend for WebDav {
     fn write(&mut self) -> Result<Option<WorkerInfo>, ChumError> {
         let client = &mut self.client;
-        let mut rng = thread_rng();
-
         /* This should be similar to how muskie generates objectids. */
-        let fname = Uuid::new_v4();
+        let fname = random_uuid(&mut self.rng);
 
         let full_path = get_path(self.wopts.target.clone(), fname.to_string());
 
         /* Randomly choose a file size from the list. */
-        let size = *self
-            .wopts
-            .distribution
-            .choose(&mut rng)
-            .expect("choosing file size failed");
+        let size = self.wopts.distribution.sample(&mut self.rng);
 
         client.url(&full_path)?;
         client.put(true)?;
@@ -86,9 +85,15 @@ impl Backend for WebDav {
          * this might make future-me less frustrated.
          */
         let b = self.buf.clone();
+        let mut payload =
+            Payload::new(&fname.to_string(), size, self.wopts.payload_seed());
+        let verify = self.wopts.verify;
         {
             let mut transfer = client.transfer();
             transfer.read_function(|into| {
+                if verify {
+                    return Ok(payload.fill(into));
+                }
                 /* This should be memcpy, thus pretty fast. */
                 into.copy_from_slice(&b);
                 Ok(into.len())
@@ -104,22 +109,18 @@ impl Backend for WebDav {
          */
         let code = client.response_code()?;
         if code == 201 || code == 204 || code == 200 {
-            /*
-             * XXX want to use .as_secs_f64() or similar once we can move
-             * to rust 1.38+
-             */
-            let ttfb = client.starttransfer_time().unwrap().as_millis();
-            let rtt = client.total_time().unwrap().as_millis();
-
-            if self.wopts.read_queue {
-                self.wopts.queue.lock().unwrap().insert(fname.to_string());
-            }
+            let ttfb = client.starttransfer_time()?;
+            let rtt = client.total_time()?;
+
+            let rec = ObjectRecord::new(None, fname, size);
+            self.wopts.written(rec, &mut self.rng);
             Ok(Some(WorkerInfo {
                 id: thread::current().id(),
                 op: Operation::Write,
                 size,
                 ttfb,
                 rtt,
+                age: None,
             }))
         } else {
             Err(ChumError::new(&format!(
@@ -131,30 +132,25 @@ impl Backend for WebDav {
 
     fn read(&mut self) -> Result<Option<WorkerInfo>, ChumError> {
         let client = &mut self.client;
-        let fname: String;
 
-        /*
-         * Create a scope here to ensure that we don't keep the queue locked
-         * for longer than necessary.
-         */
-        {
-            let mut q = self.wopts.queue.lock().unwrap();
-            let qi = q.get();
-            if qi.is_none() {
-                return Ok(None);
-            }
-            let qi = qi.unwrap();
-
-            fname = qi.clone();
-            client.url(&get_path(self.wopts.target.clone(), fname.clone()))?;
-        }
+        let rec = match self.wopts.queue.get(&mut self.rng) {
+            Some(rec) => rec,
+            None => return Ok(None),
+        };
+        client.url(&get_path(self.wopts.target.clone(), rec.name()))?;
         client.get(true)?;
 
         let mut size = 0;
+        let mut verifier =
+            Verifier::new(&rec.name(), self.wopts.payload_seed());
+        let verify = self.wopts.verify;
         {
             let mut transfer = client.transfer();
             transfer.write_function(|data| {
                 size += data.len();
+                if verify {
+                    verifier.update(data);
+                }
                 Ok(data.len())
             })?;
             transfer.perform()?;
@@ -162,58 +158,72 @@ impl Backend for WebDav {
 
         let code = client.response_code()?;
         if code == 200 {
-            let ttfb = client.starttransfer_time()?.as_millis();
-            let rtt = client.total_time()?.as_millis();
+            let ttfb = client.starttransfer_time()?;
+            let rtt = client.total_time()?;
+            if verify {
+                verifier.finish()?;
+            }
+            rec.check_read(size as u64, None)?;
             Ok(Some(WorkerInfo {
                 id: thread::current().id(),
                 op: Operation::Read,
                 size: size as u64,
                 ttfb,
                 rtt,
+                age: rec.age(),
             }))
         } else {
             Err(ChumError::new(&format!(
                 "Reading {} failed: {}",
-                fname, code
+                rec.name(),
+                code
             )))
         }
     }
 
     fn delete(&mut self) -> Result<Option<WorkerInfo>, ChumError> {
         let client = &mut self.client;
-        let fname: String;
+
+        let rec = match self.wopts.queue.remove(&mut self.rng) {
+            Some(rec) => rec,
+            None => return Ok(None),
+        };
+        let fname = rec.name();
+
+        client.url(&get_path(self.wopts.target.clone(), fname.clone()))?;
+        client.custom_request("DELETE")?;
+        let res = client.perform();
 
         /*
-         * Create a scope here to ensure that we don't keep the queue locked
-         * for longer than necessary.
+         * Re-insert the object to make it available for future read or delete
+         * operations if there was an error during the delete.
          */
-        {
-            let mut q = self.wopts.queue.lock().unwrap();
-            let qi = q.get();
-            if qi.is_none() {
-                return Ok(None);
+        let code = match res.and_then(|_| client.response_code()) {
+            Ok(code) => code,
+            Err(e) => {
+                self.wopts.queue.insert(rec, &mut self.rng);
+                return Err(e.into());
             }
-            let qi = qi.unwrap();
-
-            fname = qi.clone();
-            client.url(&get_path(self.wopts.target.clone(), fname.clone()))?;
-        }
+        };
 
-        client.custom_request("DELETE")?;
-        client.perform()?;
-
-        let code = client.response_code()?;
-        if code == 200 {
-            let ttfb = client.starttransfer_time()?.as_millis();
-            let rtt = client.total_time()?.as_millis();
+        /*
+         * Servers answer with a 200 or a 204 once the object is gone. A 404
+         * means it's gone already, so there's nothing to put back either.
+         */
+        if code == 200 || code == 204 || code == 404 {
+            let ttfb = client.starttransfer_time()?;
+            let rtt = client.total_time()?;
+            self.wopts.deleted(&rec);
             Ok(Some(WorkerInfo {
                 id: thread::current().id(),
                 op: Operation::Delete,
                 size: 0,
                 ttfb,
                 rtt,
+                age: rec.age(),
             }))
         } else {
+            self.wopts.queue.insert(rec, &mut self.rng);
             Err(ChumError::new(&format!(
                 "Deleting {} failed: {}",
                 fname, code
@@ -228,3 +238,101 @@ impl Backend for WebDav {
 fn get_path(target: String, fname: String) -> String {
     format!("http://{}/api/v1/object/{}", target, fname)
 }
+
+/*
+ * Objects are all in one collection on the server. It's listed with a PROPFIND
+ * request, and only objects named with a UUID are deleted.
+ */
+pub struct WebDavCleaner {
+    target: String,
+    client: Easy,
+}
+
+/* The names of the objects in a PROPFIND response. */
+pub fn propfind_names(body: &str) -> Vec<String> {
+    let href_re =
+        Regex::new(r"<(?:\w+:)?href>([^<]*)</(?:\w+:)?href>").unwrap();
+    href_re
+        .captures_iter(body)
+        .filter_map(|caps| {
+            let name = caps[1].trim_end_matches('/').rsplit('/').next()?;
+            Uuid::parse_str(name).ok()?;
+            Some(name.to_string())
+        })
+        .collect()
+}
+
+impl WebDavCleaner {
+    pub fn new(target: &str) -> WebDavCleaner {
+        WebDavCleaner {
+            target: target.to_string(),
+            client: Easy::new(),
+        }
+    }
+}
+
+impl Cleaner for WebDavCleaner {
+    fn list(
+        &mut self,
+        found: &mut dyn FnMut(Vec<String>) -> bool,
+    ) -> Result<(), ChumError> {
+        let client = &mut self.client;
+        let mut headers = List::new();
+        headers.append("Depth: 1")?;
+        client.url(&get_path(self.target.clone(), String::new()))?;
+        client.custom_request("PROPFIND")?;
+        client.http_headers(headers)?;
+
+        let mut body = Vec::new();
+        {
+            let mut transfer = client.transfer();
+            transfer.write_function(|data| {
+                body.extend_from_slice(data);
+                Ok(data.len())
+            })?;
+            transfer.perform()?;
+        }
+
+        /* 207 Multi-Status is the only successful response to a PROPFIND. */
+        let code = client.response_code()?;
+        if code != 207 {
+            return Err(ChumError::new(&format!(
+                "Listing objects failed: {}",
+                code
+            )));
+        }
+
+        let names = propfind_names(&String::from_utf8_lossy(&body));
+        for batch in names.chunks(BATCH_SIZE) {
+            if !found(batch.to_vec()) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /* Objects that are already gone don't count as errors. */
+    fn delete(&mut self, objs: &[String]) -> Vec<ChumError> {
+        let client = &mut self.client;
+        let mut errors = Vec::new();
+        for fname in objs {
+            let res = client
+                .url(&get_path(self.target.clone(), fname.clone()))
+                .and_then(|_| client.custom_request("DELETE"))
+                .and_then(|_| client.perform())
+                .and_then(|_| client.response_code());
+            match res {
+                Ok(200) | Ok(204) | Ok(404) => (),
+                Ok(code) => errors.push(ChumError::new(&format!(
+                    "Deleting {} failed: {}",
+                    fname, code
+                ))),
+                Err(e) => errors.push(ChumError::new(&format!(
+                    "Deleting {} failed: {}",
+                    fname, e
+                ))),
+            }
+        }
+        errors
+    }
+}
@@ -6,34 +6,65 @@
  * Copyright 2020 Joyent, Inc.
  */
 
+use hdrhistogram::Histogram;
 use rand::prelude::*;
+use rand::rngs::StdRng;
+use serde_json::json;
 use std::sync::{
+    atomic::{AtomicBool, Ordering},
     mpsc::{SendError, Sender},
     Arc, Mutex,
 };
 use std::time;
 use std::{thread, thread::ThreadId};
 
+use crate::budget::OpBudget;
+use crate::distribution::SizeDistribution;
 use crate::fs::Fs;
-use crate::queue::Queue;
+use crate::queue::ShardedQueue;
+use crate::rate::Pacer;
+use crate::record::{ListEntry, ObjectRecord};
 use crate::s3::S3;
 use crate::state::State;
-use crate::utils::ChumError;
+use crate::utils::{ChumError, ErrorKind};
+use crate::verify::DEF_SEED;
 use crate::webdav::WebDav;
 
+use uuid::{Builder, Uuid, Variant, Version};
+
 pub const DIR: &str = "chum";
 
+/* Significant figures kept by the latency histograms. */
+const HIST_SIGFIG: u8 = 3;
+
+/*
+ * Every random choice a worker makes comes from its own generator: which
+ * operation to run, object sizes and names, and which object to read or
+ * delete. When the user gives a seed, each worker's generator is seeded in turn
+ * from this one, so two runs with the same settings make the same choices.
+ */
+pub type Seeder = Arc<Mutex<StdRng>>;
+
+/* Latency percentiles reported alongside the maximum. */
+pub const PERCENTILES: [(&str, f64); 4] =
+    [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p99.9", 0.999)];
+
 #[derive(Clone)]
 pub struct WorkerOptions {
     pub protocol: String,
     pub read_queue: bool,
     pub operations: Vec<Operation>,
-    pub distribution: Vec<u64>,
+    pub distribution: SizeDistribution,
     pub target: String,
     pub sleep: u64,
-    pub tx: Sender<Result<WorkerInfo, ChumError>>,
+    pub pacer: Option<Arc<Pacer>>,
+    pub budget: Option<Arc<OpBudget>>,
     pub debug_tx: Option<Sender<State>>,
-    pub queue: Arc<Mutex<Queue<String>>>,
+    pub queue: Arc<ShardedQueue<ObjectRecord>>,
+    pub verify: bool,
+    pub seed: Option<u64>,
+    pub seeder: Option<Seeder>,
+    pub write_list: Option<Sender<ListEntry>>,
 
     /* FS worker. */
     pub sync: bool,
@@ -42,23 +73,52 @@ pub struct WorkerOptions {
     pub http2: bool,
 }
 
+/*
+ * A failed operation. The operation is kept so that errors can be counted
+ * separately for reads, writes, etc.
+ */
+#[derive(Debug)]
+pub struct WorkerError {
+    pub id: ThreadId,
+    pub op: Operation,
+    pub err: ChumError,
+}
+
+/* What workers send to the stat thread for every operation performed. */
+pub type WorkerResult = Result<WorkerInfo, WorkerError>;
+
 #[derive(Debug)]
 pub struct WorkerInfo {
     pub id: ThreadId,
     pub op: Operation, /* e.g. 'read' or 'write' */
     pub size: u64,     /* in bytes */
-    pub ttfb: u128,    /* millis */
-    pub rtt: u128,     /* millis */
+    pub ttfb: time::Duration,
+    pub rtt: time::Duration,
+    pub age: Option<time::Duration>, /* of the object read or deleted */
 }
 
 /*
- * WorkerInfos can be aggregated into WorkerStats.
+ * WorkerInfos can be aggregated into WorkerStats. Alongside the sums we keep
+ * a histogram of each latency so we can report percentiles. WorkerStats from
+ * different threads (or ticks) can be merged together.
+ *
+ * The ages of the objects read or deleted are kept in a histogram too, in
+ * milliseconds.
+ *
+ * Data integrity failures found in verify mode aren't counted in 'errors'.
  */
 pub struct WorkerStat {
     pub objs: u64,
     pub data: u64,
-    pub ttfb: u128,
-    pub rtt: u128,
+    pub errors: u64,
+    pub corrupt: u64,
+    pub truncated: u64,
+    pub misdirected: u64,
+    pub ttfb: time::Duration,
+    pub rtt: time::Duration,
+    pub ttfb_hist: Histogram<u64>,
+    pub rtt_hist: Histogram<u64>,
+    pub age_hist: Histogram<u64>,
 }
 
 fn bytes_to_human(bytes: u64) -> String {
@@ -66,13 +126,34 @@ fn bytes_to_human(bytes: u64) -> String {
     format!("{:.3}MB", bytes / 1024 / 1024)
 }
 
+/* Latencies are kept in microseconds, but reported in milliseconds. */
+pub fn micros_to_ms(micros: u64) -> f64 {
+    micros as f64 / 1000.0
+}
+
+pub fn duration_to_ms(d: time::Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn new_histogram() -> Histogram<u64> {
+    /* This auto-resizes, so there is no upper bound on recorded values. */
+    Histogram::new(HIST_SIGFIG).expect("failed to create histogram")
+}
+
 impl WorkerStat {
     pub fn new() -> Self {
         WorkerStat {
             objs: 0,
             data: 0,
-            ttfb: 0,
-            rtt: 0,
+            errors: 0,
+            corrupt: 0,
+            truncated: 0,
+            misdirected: 0,
+            ttfb: time::Duration::from_secs(0),
+            rtt: time::Duration::from_secs(0),
+            ttfb_hist: new_histogram(),
+            rtt_hist: new_histogram(),
+            age_hist: new_histogram(),
         }
     }
     pub fn add_result(&mut self, res: &WorkerInfo) {
@@ -80,23 +161,171 @@ impl WorkerStat {
         self.data += res.size;
         self.ttfb += res.ttfb;
         self.rtt += res.rtt;
+        self.ttfb_hist
+            .saturating_record(res.ttfb.as_micros() as u64);
+        self.rtt_hist.saturating_record(res.rtt.as_micros() as u64);
+        if let Some(age) = res.age {
+            self.age_hist.saturating_record(age.as_millis() as u64);
+        }
+    }
+
+    pub fn add_error(&mut self, kind: ErrorKind) {
+        match kind {
+            ErrorKind::Other => self.errors += 1,
+            ErrorKind::Corrupt => self.corrupt += 1,
+            ErrorKind::Truncated => self.truncated += 1,
+            ErrorKind::Misdirected => self.misdirected += 1,
+        }
+    }
+
+    pub fn integrity_errors(&self) -> u64 {
+        self.corrupt + self.truncated + self.misdirected
+    }
+
+    pub fn merge(&mut self, other: &WorkerStat) {
+        self.objs += other.objs;
+        self.data += other.data;
+        self.errors += other.errors;
+        self.corrupt += other.corrupt;
+        self.truncated += other.truncated;
+        self.misdirected += other.misdirected;
+        self.ttfb += other.ttfb;
+        self.rtt += other.rtt;
+        self.ttfb_hist
+            .add(&other.ttfb_hist)
+            .expect("failed to merge histograms");
+        self.rtt_hist
+            .add(&other.rtt_hist)
+            .expect("failed to merge histograms");
+        self.age_hist
+            .add(&other.age_hist)
+            .expect("failed to merge histograms");
     }
 
     pub fn clear(&mut self) {
         self.objs = 0;
         self.data = 0;
-        self.ttfb = 0;
-        self.rtt = 0;
+        self.errors = 0;
+        self.corrupt = 0;
+        self.truncated = 0;
+        self.misdirected = 0;
+        self.ttfb = time::Duration::from_secs(0);
+        self.rtt = time::Duration::from_secs(0);
+        self.ttfb_hist.reset();
+        self.rtt_hist.reset();
+        self.age_hist.reset();
+    }
+
+    /* Average latencies in milliseconds. */
+    pub fn avg_ttfb(&self) -> f64 {
+        if self.objs == 0 {
+            return 0.0;
+        }
+        duration_to_ms(self.ttfb) / self.objs as f64
+    }
+
+    pub fn avg_rtt(&self) -> f64 {
+        if self.objs == 0 {
+            return 0.0;
+        }
+        duration_to_ms(self.rtt) / self.objs as f64
+    }
+
+    /*
+     * Latency percentiles and the maximum in microseconds, in the order
+     * they're reported.
+     */
+    pub fn percentiles(hist: &Histogram<u64>) -> Vec<u64> {
+        let mut vals: Vec<u64> = PERCENTILES
+            .iter()
+            .map(|(_, q)| hist.value_at_quantile(*q))
+            .collect();
+        vals.push(hist.max());
+        vals
+    }
+
+    pub fn percentiles_to_json(hist: &Histogram<u64>) -> serde_json::Value {
+        let mut map = serde_json::Map::new();
+        let names = PERCENTILES
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(std::iter::once("max"));
+        for (name, val) in names.zip(WorkerStat::percentiles(hist)) {
+            map.insert(name.to_string(), json!(micros_to_ms(val)));
+        }
+        serde_json::Value::Object(map)
+    }
+
+    /* Object age percentiles and the maximum, in seconds. */
+    pub fn ages_to_json(hist: &Histogram<u64>) -> serde_json::Value {
+        let mut map = serde_json::Map::new();
+        let names = PERCENTILES
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(std::iter::once("max"));
+        for (name, val) in names.zip(WorkerStat::percentiles(hist)) {
+            map.insert(name.to_string(), json!(val as f64 / 1000.0));
+        }
+        serde_json::Value::Object(map)
+    }
+
+    /*
+     * For machine readable output. Latencies are in milliseconds and object
+     * ages in seconds.
+     */
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "objects": self.objs,
+            "bytes": self.data,
+            "errors": self.errors,
+            "corrupt": self.corrupt,
+            "truncated": self.truncated,
+            "misdirected": self.misdirected,
+            "ttfb_avg_ms": self.avg_ttfb(),
+            "rtt_avg_ms": self.avg_rtt(),
+            "ttfb_ms": WorkerStat::percentiles_to_json(&self.ttfb_hist),
+            "rtt_ms": WorkerStat::percentiles_to_json(&self.rtt_hist),
+            "age_s": WorkerStat::ages_to_json(&self.age_hist),
+        })
+    }
+
+    pub fn serialize_percentiles(hist: &Histogram<u64>) -> String {
+        let vals = WorkerStat::percentiles(hist);
+        let names: Vec<&str> = PERCENTILES
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(std::iter::once("max"))
+            .collect();
+        let vals: Vec<String> = vals
+            .iter()
+            .map(|v| format!("{:.3}", micros_to_ms(*v)))
+            .collect();
+        format!("{} {}ms", names.join("/"), vals.join("/"))
+    }
+
+    pub fn serialize_ages(hist: &Histogram<u64>) -> String {
+        let vals = WorkerStat::percentiles(hist);
+        let names: Vec<&str> = PERCENTILES
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(std::iter::once("max"))
+            .collect();
+        let vals: Vec<String> = vals
+            .iter()
+            .map(|v| format!("{:.1}", *v as f64 / 1000.0))
+            .collect();
+        format!("{} {}s", names.join("/"), vals.join("/"))
     }
 
     /* For easy printing when the caller doesn't care about time. */
     pub fn serialize_relative(&mut self) -> String {
         format!(
-            "{} objects, {}, avg ttfb {}ms, avg rtt {}ms",
+            "{} objects, {}, avg ttfb {:.3}ms, avg rtt {:.3}ms, rtt {}",
             self.objs,
             bytes_to_human(self.data),
-            self.ttfb / u128::from(self.objs),
-            self.rtt / u128::from(self.objs)
+            self.avg_ttfb(),
+            self.avg_rtt(),
+            WorkerStat::serialize_percentiles(&self.rtt_hist)
         )
     }
 
@@ -105,15 +334,85 @@ impl WorkerStat {
      * average throughput).
      */
     pub fn serialize_absolute(&mut self, d: u64) -> String {
-        format!(
-            "{} objects, {}, {}s, avg {} objs/s, avg {}/s",
+        let mut line = format!(
+            "{} objects, {}, {}s, avg {} objs/s, avg {}/s, rtt {}",
             self.objs,
             bytes_to_human(self.data),
             d,
             self.objs / d,
-            bytes_to_human(self.data / d)
+            bytes_to_human(self.data / d),
+            WorkerStat::serialize_percentiles(&self.rtt_hist)
+        );
+        /* Only reads and deletes of objects of known age have one. */
+        if !self.age_hist.is_empty() {
+            line.push_str(&format!(
+                ", age {}",
+                WorkerStat::serialize_ages(&self.age_hist)
+            ));
+        }
+        line
+    }
+
+    pub fn serialize_integrity(&self) -> String {
+        format!(
+            "{} corrupt, {} truncated, {} misdirected",
+            self.corrupt, self.truncated, self.misdirected
         )
     }
+
+    /*
+     * For sending stats to another chum process. Unlike to_json() this keeps
+     * everything needed to merge the stats on the other end. Latency sums are
+     * in microseconds and histograms are lists of [value, count] pairs.
+     */
+    pub fn to_wire(&self) -> serde_json::Value {
+        let hist = |h: &Histogram<u64>| -> Vec<[u64; 2]> {
+            h.iter_recorded()
+                .map(|v| [v.value_iterated_to(), v.count_at_value()])
+                .collect()
+        };
+        json!({
+            "objs": self.objs,
+            "data": self.data,
+            "errors": self.errors,
+            "corrupt": self.corrupt,
+            "truncated": self.truncated,
+            "misdirected": self.misdirected,
+            "ttfb": self.ttfb.as_micros() as u64,
+            "rtt": self.rtt.as_micros() as u64,
+            "ttfb_hist": hist(&self.ttfb_hist),
+            "rtt_hist": hist(&self.rtt_hist),
+            "age_hist": hist(&self.age_hist),
+        })
+    }
+
+    pub fn from_wire(val: &serde_json::Value) -> Result<WorkerStat, ChumError> {
+        let bad = || ChumError::new("malformed stats");
+        let num = |key: &str| val[key].as_u64().ok_or_else(bad);
+        let hist = |key: &str| -> Result<Histogram<u64>, ChumError> {
+            let mut h = new_histogram();
+            for pair in val[key].as_array().ok_or_else(bad)? {
+                let value = pair[0].as_u64().ok_or_else(bad)?;
+                let count = pair[1].as_u64().ok_or_else(bad)?;
+                h.record_n(value, count).map_err(|_| bad())?;
+            }
+            Ok(h)
+        };
+
+        Ok(WorkerStat {
+            objs: num("objs")?,
+            data: num("data")?,
+            errors: num("errors")?,
+            corrupt: num("corrupt")?,
+            truncated: num("truncated")?,
+            misdirected: num("misdirected")?,
+            ttfb: time::Duration::from_micros(num("ttfb")?),
+            rtt: time::Duration::from_micros(num("rtt")?),
+            ttfb_hist: hist("ttfb_hist")?,
+            rtt_hist: hist("rtt_hist")?,
+            age_hist: hist("age_hist")?,
+        })
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -150,17 +449,76 @@ impl std::str::FromStr for Operation {
     }
 }
 
+impl WorkerOptions {
+    /* Verifiable payloads are generated from the user's seed, if any. */
+    pub fn payload_seed(&self) -> u64 {
+        self.seed.unwrap_or(DEF_SEED)
+    }
+
+    /*
+     * Record an object that a worker just wrote. It becomes a candidate for
+     * later reads and deletes, and goes in the write list if there is one.
+     */
+    pub fn written<R: Rng>(&self, rec: ObjectRecord, rng: &mut R) {
+        if let Some(list) = &self.write_list {
+            /* The write list stops listening if it can't write the file. */
+            let _ = list.send(ListEntry::Written(rec.clone()));
+        }
+        if self.read_queue && self.queue.insert(rec, rng) {
+            eprintln!(
+                "the read queue is full, so objects are being dropped from \
+                 it and won't be read or deleted (see --queue-cap)"
+            );
+        }
+    }
+
+    /*
+     * Record an object that a worker just deleted, so that later runs reading
+     * the write list leave it out.
+     */
+    pub fn deleted(&self, rec: &ObjectRecord) {
+        if let Some(list) = &self.write_list {
+            let _ = list.send(ListEntry::Deleted(rec.clone()));
+        }
+    }
+}
+
+/* A generator for a worker, seeded from 'seeder' when the run is seeded. */
+pub fn new_rng(seeder: &Option<Seeder>) -> StdRng {
+    match seeder {
+        Some(seeder) => StdRng::seed_from_u64(seeder.lock().unwrap().gen()),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/* A random (version 4) UUID drawn from 'rng'. */
+pub fn random_uuid<R: Rng>(rng: &mut R) -> Uuid {
+    let mut bytes = [0u8; 16];
+    rng.fill(&mut bytes);
+    Builder::from_bytes(bytes)
+        .set_variant(Variant::RFC4122)
+        .set_version(Version::Random)
+        .build()
+}
+
 pub trait Backend {
     fn write(&mut self) -> Result<Option<WorkerInfo>, ChumError>;
     fn read(&mut self) -> Result<Option<WorkerInfo>, ChumError>;
     fn delete(&mut self) -> Result<Option<WorkerInfo>, ChumError>;
 }
 
+/* How long a worker waits for a slot when the operation caps are full. */
+const CLAIM_RETRY_INTERVAL: time::Duration = time::Duration::from_millis(1);
+
 pub struct Worker {
     backend: Box<dyn Backend>,
-    tx: Sender<Result<WorkerInfo, ChumError>>,
+    tx: Sender<WorkerResult>,
     pause: u64,
+    pacer: Option<Arc<Pacer>>,
+    budget: Option<Arc<OpBudget>>,
     ops: Vec<Operation>,
+    rng: StdRng,
+    stop: Arc<AtomicBool>,
 }
 
 /*
@@ -168,15 +526,28 @@ pub struct Worker {
  * in the form of a WorkerInfo for every operation performed.
  *
  * A Worker calls out to WorkerTask implementors and throws their WorkerInfo
- * into the tx mpsc to get picked up by a statistics listener.
+ * into the tx mpsc to get picked up by a statistics listener. The worker runs
+ * until the 'stop' flag is set.
  */
 #[allow(clippy::too_many_arguments)]
 impl Worker {
-    pub fn new(wopts: WorkerOptions) -> Worker {
+    pub fn new(
+        wopts: WorkerOptions,
+        mut rng: StdRng,
+        tx: Sender<WorkerResult>,
+        stop: Arc<AtomicBool>,
+    ) -> Worker {
         let protocol = wopts.protocol.clone();
         let pause = wopts.sleep;
+        let pacer = wopts.pacer.clone();
+        let budget = wopts.budget.clone();
         let ops = wopts.operations.clone();
-        let tx = wopts.tx.clone();
+
+        /*
+         * The backend draws from its own generator, so the operations chosen
+         * don't depend on how many random numbers an operation used.
+         */
+        let backend_rng = StdRng::seed_from_u64(rng.gen());
 
         /*
          * Construct a client of the given type.
@@ -186,9 +557,9 @@ impl Worker {
          * we use it.
          */
         let backend: Box<dyn Backend> = match protocol.as_ref() {
-            "webdav" => Box::new(WebDav::new(wopts)),
-            "s3" => Box::new(S3::new(wopts)),
-            "fs" => Box::new(Fs::new(wopts)),
+            "webdav" => Box::new(WebDav::new(wopts, backend_rng)),
+            "s3" => Box::new(S3::new(wopts, backend_rng)),
+            "fs" => Box::new(Fs::new(wopts, backend_rng)),
             _ => panic!("unknown client protocol"),
         };
 
@@ -196,14 +567,19 @@ impl Worker {
             backend,
             tx,
             pause,
+            pacer,
+            budget,
             ops,
+            rng,
+            stop,
         }
     }
 
     pub fn process_result(
         &self,
+        op: Operation,
         res: Result<Option<WorkerInfo>, ChumError>,
-    ) -> Result<(), SendError<Result<WorkerInfo, ChumError>>> {
+    ) -> Result<(), SendError<WorkerResult>> {
         match res {
             Ok(val) => {
                 if let Some(wr) = val {
@@ -212,28 +588,92 @@ impl Worker {
                     Ok(()) /* no-op, like a read operation with an empty queue */
                 }
             }
-            Err(e) => self.tx.send(Err(e)),
+            Err(err) => self.tx.send(Err(WorkerError {
+                id: thread::current().id(),
+                op,
+                err,
+            })),
         }
     }
 
     pub fn work(&mut self) {
-        let mut rng = thread_rng();
-
         loop {
-            /* Thread exits when it receives a signal over its channel. */
+            /*
+             * Thread exits when the run is stopped, or when the stat thread
+             * hangs up its end of the channel.
+             */
+            if self.stop.load(Ordering::SeqCst) {
+                return;
+            }
 
-            let res = match self
+            let op = *self
                 .ops
-                .choose(&mut rng)
-                .expect("choosing operation failed")
-            {
+                .choose(&mut self.rng)
+                .expect("choosing operation failed");
+
+            /*
+             * With operation count caps every operation needs a slot. If
+             * there's none left for this one, wait for a slot to be given
+             * back or for the run to stop.
+             */
+            let counted = match &self.budget {
+                Some(budget) => match budget.claim(op) {
+                    Some(counted) => counted,
+                    None => {
+                        thread::sleep(CLAIM_RETRY_INTERVAL);
+                        continue;
+                    }
+                },
+                None => false,
+            };
+
+            /*
+             * When the load is paced, wait for this worker's turn. Waiting
+             * may end early because the run was stopped.
+             */
+            let intended_start = match &self.pacer {
+                Some(pacer) => {
+                    let start = pacer.wait(&self.stop);
+                    if self.stop.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    Some(start)
+                }
+                None => None,
+            };
+
+            let started = time::Instant::now();
+            let mut res = match op {
                 Operation::Read => self.backend.read(),
                 Operation::Write => self.backend.write(),
                 Operation::Delete => self.backend.delete(),
                 _ => panic!("unrecognized operator"),
             };
 
-            match self.process_result(res) {
+            if let (Some(pacer), Some(intended)) = (&self.pacer, intended_start)
+            {
+                if let Ok(Some(wi)) = &mut res {
+                    /*
+                     * Count the time spent behind schedule against the
+                     * operation. Backends that can't measure ttfb report zero,
+                     * and that's left alone.
+                     */
+                    let behind = started.saturating_duration_since(intended);
+                    wi.rtt += behind;
+                    if wi.ttfb > time::Duration::from_secs(0) {
+                        wi.ttfb += behind;
+                    }
+                    pacer.charge(wi.size);
+                }
+            }
+
+            /* Reads that fail verification still count as operations. */
+            let completed = match &res {
+                Ok(val) => val.is_some(),
+                Err(e) => e.kind() != ErrorKind::Other,
+            };
+
+            match self.process_result(op, res) {
                 Ok(_) => (),
                 Err(_) => {
                     /*
@@ -244,12 +684,20 @@ impl Worker {
                 }
             }
 
+            if let Some(budget) = &self.budget {
+                if !completed {
+                    budget.release(op, counted);
+                } else if budget.finish(op, counted) {
+                    self.stop.store(true, Ordering::SeqCst);
+                }
+            }
+
             self.sleep();
         }
     }
 
     fn sleep(&mut self) {
-        if self.pause > 0 {
+        if self.pause > 0 && self.pacer.is_none() {
             thread::sleep(time::Duration::from_millis(self.pause));
         }
     }